@@ -32,6 +32,24 @@ impl Aabb {
         Aabb::new(min, max)
     }
 
+    pub fn padded(self, delta: f32) -> Aabb {
+        let pad = Vec3::from_scalar(delta);
+        Aabb::new(self.min - pad, self.max + pad)
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn diagonal(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.diagonal();
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
     pub fn hit(&self, ray: &Ray, tmin: f32, tmax: f32) -> bool {
         for a in 0..3 {
             let inv_d = 1.0 / ray.dir[a];
@@ -0,0 +1,539 @@
+use core::f32;
+use std::f32::consts::PI;
+
+use crate::camera::Camera;
+use crate::color::{Color, BLACK, WHITE};
+use crate::film::SplatBuffer;
+use crate::hittable::{HitRecord, Hittable};
+use crate::integrator::Integrator;
+use crate::maths::{Ray, Vec3};
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+
+// Bidirectional path tracing after Veach, structured like pbrt's implementation.
+// Light subpaths start on emissive objects, every camera/light vertex pair is connected
+// and weighted with the balance heuristic. Paths that reach the camera through the
+// light subpath alone (t = 1) are splatted onto the film.
+pub struct Bdpt {
+    pub max_depth: i32,
+    pub camera: Camera,
+    pub width: i32,
+    pub height: i32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+struct Vertex {
+    kind: VertexKind,
+    point: Vec3,
+    normal: Vec3,
+    hit: Option<HitRecord>,
+    beta: Color,
+    // Area densities of generating this vertex from its predecessor and successor
+    pdf_fwd: f32,
+    pdf_rev: f32,
+    delta: bool,
+}
+
+impl Vertex {
+    fn camera(point: Vec3) -> Vertex {
+        Vertex {
+            kind: VertexKind::Camera,
+            point,
+            normal: Vec3::zero(),
+            hit: None,
+            beta: WHITE,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+        }
+    }
+
+    fn light(point: Vec3, normal: Vec3, beta: Color, pdf: f32) -> Vertex {
+        Vertex {
+            kind: VertexKind::Light,
+            point,
+            normal,
+            hit: None,
+            beta,
+            pdf_fwd: pdf,
+            pdf_rev: 0.0,
+            delta: false,
+        }
+    }
+
+    fn emitted(&self) -> Color {
+        match &self.hit {
+            Some(hit) => hit.material.emitted(),
+            None => BLACK,
+        }
+    }
+
+    // BSDF times cosine for light arriving from `from` and leaving towards `to`
+    fn bsdf(&self, from: Vec3, to: Vec3) -> Option<Color> {
+        match (&self.hit, self.kind) {
+            (Some(hit), VertexKind::Surface) => {
+                let incoming = Ray::new(from, self.point - from);
+                let (f, _) = hit.material.bsdf(&incoming, hit, to - self.point)?;
+                Some(f)
+            }
+            // Diffuse emitters send out radiance proportional to the cosine
+            (_, VertexKind::Light) => {
+                let cosine = f32::abs(Vec3::dot(self.normal, (to - self.point).normalized()));
+                Some(WHITE * cosine)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct MisVertex {
+    pdf_fwd: f32,
+    pdf_rev: f32,
+    delta: bool,
+}
+
+impl MisVertex {
+    fn from(vertex: &Vertex) -> MisVertex {
+        MisVertex {
+            pdf_fwd: vertex.pdf_fwd,
+            pdf_rev: vertex.pdf_rev,
+            delta: vertex.delta,
+        }
+    }
+}
+
+fn convert_density(pdf_dir: f32, from: &Vertex, to: &Vertex) -> f32 {
+    let w = to.point - from.point;
+    let distance2 = w.length2();
+    if distance2 <= 0.0 {
+        return 0.0;
+    }
+
+    let mut pdf = pdf_dir / distance2;
+    if to.kind != VertexKind::Camera {
+        pdf *= f32::abs(Vec3::dot(to.normal, w / distance2.sqrt()));
+    }
+    pdf
+}
+
+fn remap0(pdf: f32) -> f32 {
+    if pdf != 0.0 {
+        pdf
+    } else {
+        1.0
+    }
+}
+
+fn visible(scene: &Scene, a: Vec3, b: Vec3) -> bool {
+    let dir = b - a;
+    let distance = dir.length();
+    let shadow = Ray::new(a, dir / distance);
+    scene.world.hit(0.01, distance - 0.01, &shadow).is_none()
+}
+
+// Extends `path` by following `ray` until it escapes, is absorbed or has `max_vertices`.
+// Returns the radiance of the background if the path escaped.
+fn random_walk(
+    scene: &Scene,
+    ray: Ray,
+    beta: Color,
+    pdf_dir: f32,
+    max_vertices: usize,
+    path: &mut Vec<Vertex>,
+    sampler: &mut dyn Sampler,
+) -> Option<Color> {
+    let mut ray = ray;
+    let mut beta = beta;
+    let mut pdf_fwd = pdf_dir;
+
+    while path.len() < max_vertices {
+        let hit = match scene.world.hit(0.01, f32::INFINITY, &ray) {
+            Some(hit) => hit,
+            None => return Some(beta * scene.background.radiance(ray.dir)),
+        };
+
+        let scatter = hit.material.scatter(ray, &hit, sampler);
+        let sampled = scatter.map(|(_, scattered)| {
+            let reverse = Ray::new(scattered.at(1.0), -scattered.dir);
+            let forward = hit.material.bsdf(&ray, &hit, scattered.dir);
+            let backward = hit.material.bsdf(&reverse, &hit, -ray.dir);
+            (forward, backward)
+        });
+
+        let mut vertex = Vertex {
+            kind: VertexKind::Surface,
+            point: hit.point,
+            normal: hit.normal,
+            hit: None,
+            beta,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: matches!(sampled, Some((None, _))),
+        };
+        let prev = path.last().expect("random walk without an endpoint");
+        vertex.pdf_fwd = convert_density(pdf_fwd, prev, &vertex);
+        vertex.hit = Some(hit);
+        path.push(vertex);
+
+        let (attenuation, scattered) = match scatter {
+            Some(scatter) => scatter,
+            None => break,
+        };
+
+        let (pdf_rev, pdf_next) = match sampled {
+            Some((Some((_, forward)), backward)) => {
+                (backward.map(|(_, pdf)| pdf).unwrap_or(0.0), forward)
+            }
+            _ => (0.0, 0.0),
+        };
+
+        let count = path.len();
+        let pdf_rev = convert_density(pdf_rev, &path[count - 1], &path[count - 2]);
+        path[count - 2].pdf_rev = pdf_rev;
+
+        beta = beta * attenuation;
+        pdf_fwd = pdf_next;
+        ray = scattered;
+    }
+
+    None
+}
+
+impl Bdpt {
+    fn film_area(&self) -> f32 {
+        // Camera rays cover s in [0, w / (w - 1)] and t in [0, h / (h - 1)]
+        let coverage = self.width as f32 / (self.width as f32 - 1.0) * self.height as f32
+            / (self.height as f32 - 1.0);
+        self.camera.film_area() * coverage
+    }
+
+    // Solid angle density of the camera generating a ray along `dir`
+    fn camera_pdf_dir(&self, dir: Vec3) -> f32 {
+        let cosine = Vec3::dot(dir.normalized(), self.camera.forward());
+        if cosine <= 0.0 {
+            return 0.0;
+        }
+        1.0 / (self.film_area() * cosine * cosine * cosine)
+    }
+
+    // Area density at `next` of `vertex` sampling it, having been reached from `prev`
+    fn pdf(&self, prev: Option<&Vertex>, vertex: &Vertex, next: &Vertex) -> f32 {
+        let pdf_dir = match vertex.kind {
+            VertexKind::Camera => self.camera_pdf_dir(next.point - vertex.point),
+            VertexKind::Light => return light_pdf(vertex, next),
+            VertexKind::Surface => {
+                let (hit, prev) = match (&vertex.hit, prev) {
+                    (Some(hit), Some(prev)) => (hit, prev),
+                    _ => return 0.0,
+                };
+                let incoming = Ray::new(prev.point, vertex.point - prev.point);
+                match hit.material.bsdf(&incoming, hit, next.point - vertex.point) {
+                    Some((_, pdf)) => pdf,
+                    None => 0.0,
+                }
+            }
+        };
+        convert_density(pdf_dir, vertex, next)
+    }
+
+    fn light_subpath(&self, scene: &Scene, sampler: &mut dyn Sampler) -> Vec<Vertex> {
+        let mut path = Vec::new();
+        let sample = match scene.lights.sample_emitter_surface(sampler) {
+            Some(sample) => sample,
+            None => return path,
+        };
+
+        // Emit from either side with a cosine distribution
+        let mut normal = sample.normal;
+        if sampler.next_1d() < 0.5 {
+            normal = -normal;
+        }
+        let mut dir = normal + Vec3::random_unit_vector(sampler);
+        if dir.length2() < f32::EPSILON {
+            dir = normal;
+        }
+        let dir = dir.normalized();
+        let pdf_dir = Vec3::dot(dir, normal) / (2.0 * PI);
+
+        let beta = sample.emission * (1.0 / sample.pdf);
+        path.push(Vertex::light(sample.point, sample.normal, beta, sample.pdf));
+        if pdf_dir > 0.0 {
+            let beta = beta * (Vec3::dot(dir, normal) / pdf_dir);
+            let max_vertices = self.max_depth as usize + 1;
+            let ray = Ray::new(sample.point, dir);
+            random_walk(scene, ray, beta, pdf_dir, max_vertices, &mut path, sampler);
+        }
+        path
+    }
+
+    // Unweighted contribution of the strategy that connects the ends of `light` and `camera`,
+    // plus the endpoint that was sampled to make the connection, if any
+    fn connect(
+        &self,
+        scene: &Scene,
+        light: &[Vertex],
+        camera: &[Vertex],
+        splat: &mut Option<(f32, f32)>,
+        sampler: &mut dyn Sampler,
+    ) -> (Color, Option<Vertex>) {
+        let (s, t) = (light.len(), camera.len());
+        if s == 0 {
+            let pt = &camera[t - 1];
+            return (pt.beta * pt.emitted(), None);
+        }
+
+        if t == 1 {
+            let qs = &light[s - 1];
+            if qs.delta {
+                return (BLACK, None);
+            }
+
+            let lens_point = self.camera.sample_lens(sampler);
+            let (u, v) = match self.camera.project(lens_point, qs.point) {
+                Some(uv) => uv,
+                None => return (BLACK, None),
+            };
+
+            let from = if s > 1 { light[s - 2].point } else { qs.point };
+            let f = match qs.bsdf(from, lens_point) {
+                Some(f) if !f.is_black() => f,
+                _ => return (BLACK, None),
+            };
+
+            let dir = qs.point - lens_point;
+            let cosine = Vec3::dot(dir.normalized(), self.camera.forward());
+            let importance = 1.0 / (self.film_area() * cosine * cosine * cosine * dir.length2());
+            if !visible(scene, lens_point, qs.point) {
+                return (BLACK, None);
+            }
+
+            *splat = Some((u, v));
+            return (qs.beta * f * importance, Some(Vertex::camera(lens_point)));
+        }
+
+        let pt = &camera[t - 1];
+        if pt.delta {
+            return (BLACK, None);
+        }
+        let pt_from = camera[t - 2].point;
+
+        if s == 1 {
+            let sample = match scene.lights.sample_emitter_surface(sampler) {
+                Some(sample) => sample,
+                None => return (BLACK, None),
+            };
+            let beta = sample.emission * (1.0 / sample.pdf);
+            let qs = Vertex::light(sample.point, sample.normal, beta, sample.pdf);
+
+            let f = match (pt.bsdf(pt_from, qs.point), qs.bsdf(qs.point, pt.point)) {
+                (Some(fp), Some(fq)) => fp * fq,
+                _ => return (BLACK, None),
+            };
+            if f.is_black() || !visible(scene, pt.point, qs.point) {
+                return (BLACK, None);
+            }
+
+            let distance2 = (qs.point - pt.point).length2();
+            return (pt.beta * f * qs.beta * (1.0 / distance2), Some(qs));
+        }
+
+        let qs = &light[s - 1];
+        if qs.delta {
+            return (BLACK, None);
+        }
+        let qs_from = light[s - 2].point;
+
+        let f = match (pt.bsdf(pt_from, qs.point), qs.bsdf(qs_from, pt.point)) {
+            (Some(fp), Some(fq)) => fp * fq,
+            _ => return (BLACK, None),
+        };
+        if f.is_black() || !visible(scene, pt.point, qs.point) {
+            return (BLACK, None);
+        }
+
+        let distance2 = (qs.point - pt.point).length2();
+        (pt.beta * f * qs.beta * (1.0 / distance2), None)
+    }
+
+    fn mis_weight(
+        &self,
+        scene: &Scene,
+        light: &[Vertex],
+        camera: &[Vertex],
+        sampled: Option<&Vertex>,
+        s: usize,
+        t: usize,
+    ) -> f32 {
+        if s + t == 2 {
+            return 1.0;
+        }
+
+        let pt = match (t, sampled) {
+            (1, Some(vertex)) => vertex,
+            _ => &camera[t - 1],
+        };
+        let qs = match (s, sampled) {
+            (0, _) => None,
+            (1, Some(vertex)) => Some(vertex),
+            _ => Some(&light[s - 1]),
+        };
+        let pt_minus = if t > 1 { Some(&camera[t - 2]) } else { None };
+        let qs_minus = if s > 1 { Some(&light[s - 2]) } else { None };
+
+        let mut cameras: Vec<MisVertex> = camera[..t].iter().map(MisVertex::from).collect();
+        let mut lights: Vec<MisVertex> = light[..s].iter().map(MisVertex::from).collect();
+        cameras[t - 1] = MisVertex::from(pt);
+        cameras[t - 1].delta = false;
+
+        // Densities of the reverse direction for the vertices next to the connection
+        cameras[t - 1].pdf_rev = match qs {
+            Some(qs) => self.pdf(qs_minus, qs, pt),
+            None => match &pt.hit {
+                Some(hit) => scene.lights.emitter_surface_pdf(hit.object_id),
+                None => 0.0,
+            },
+        };
+
+        if let Some(pt_minus) = pt_minus {
+            cameras[t - 2].pdf_rev = match qs {
+                Some(qs) => self.pdf(Some(qs), pt, pt_minus),
+                None => light_pdf(pt, pt_minus),
+            };
+        }
+
+        if let Some(qs) = qs {
+            lights[s - 1] = MisVertex::from(qs);
+            lights[s - 1].delta = false;
+            lights[s - 1].pdf_rev = self.pdf(pt_minus, pt, qs);
+
+            if let Some(qs_minus) = qs_minus {
+                lights[s - 2].pdf_rev = self.pdf(Some(pt), qs, qs_minus);
+            }
+        }
+
+        let mut sum = 0.0;
+        let mut ratio = 1.0;
+        for i in (1..t).rev() {
+            ratio *= remap0(cameras[i].pdf_rev) / remap0(cameras[i].pdf_fwd);
+            if !cameras[i].delta && !cameras[i - 1].delta {
+                sum += ratio;
+            }
+        }
+
+        ratio = 1.0;
+        for i in (0..s).rev() {
+            ratio *= remap0(lights[i].pdf_rev) / remap0(lights[i].pdf_fwd);
+            let delta_before = i > 0 && lights[i - 1].delta;
+            if !lights[i].delta && !delta_before {
+                sum += ratio;
+            }
+        }
+
+        1.0 / (1.0 + sum)
+    }
+
+    // Analytic lights can only be reached by sampling them directly, so this is the only
+    // strategy for them and needs no weighting
+    fn analytic_lights(
+        &self,
+        scene: &Scene,
+        camera: &[Vertex],
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let mut radiance = BLACK;
+        for t in 2..=camera.len() {
+            let pt = &camera[t - 1];
+            if pt.delta {
+                continue;
+            }
+            for light in scene.lights.analytic() {
+                if let Some(sample) = light.sample(pt.point, sampler) {
+                    let to = pt.point + sample.dir;
+                    if let Some(f) = pt.bsdf(camera[t - 2].point, to) {
+                        let shadow = Ray::new(pt.point, sample.dir);
+                        if !f.is_black()
+                            && scene.world.hit(0.01, sample.distance, &shadow).is_none()
+                        {
+                            radiance += pt.beta * f * sample.weight;
+                        }
+                    }
+                }
+            }
+        }
+        radiance
+    }
+}
+
+// Area density at `next` of a diffuse emitter at `light` sending light towards it
+fn light_pdf(light: &Vertex, next: &Vertex) -> f32 {
+    let dir = (next.point - light.point).normalized();
+    let pdf_dir = f32::abs(Vec3::dot(light.normal, dir)) / (2.0 * PI);
+    convert_density(pdf_dir, light, next)
+}
+
+impl Integrator for Bdpt {
+    fn radiance(
+        &self,
+        ray: Ray,
+        scene: &Scene,
+        splats: &mut SplatBuffer,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let mut camera = vec![Vertex::camera(ray.origin)];
+        let pdf_dir = self.camera_pdf_dir(ray.dir);
+        let max_vertices = self.max_depth as usize + 2;
+        let escaped = random_walk(
+            scene,
+            ray,
+            WHITE,
+            pdf_dir,
+            max_vertices,
+            &mut camera,
+            sampler,
+        );
+        let light = self.light_subpath(scene, sampler);
+
+        // Nothing but the camera path can find the background
+        let mut radiance = escaped.unwrap_or(BLACK);
+        radiance += self.analytic_lights(scene, &camera, sampler);
+
+        for t in 1..=camera.len() {
+            for s in 0..=light.len() {
+                let depth = s as i32 + t as i32 - 2;
+                if (s == 1 && t == 1) || depth < 0 || depth > self.max_depth {
+                    continue;
+                }
+
+                let mut splat = None;
+                let (contribution, sampled) =
+                    self.connect(scene, &light[..s], &camera[..t], &mut splat, sampler);
+                if contribution.is_black() {
+                    continue;
+                }
+
+                let weight = self.mis_weight(scene, &light, &camera, sampled.as_ref(), s, t);
+                match splat {
+                    Some((u, v)) => {
+                        let x = f32::floor(u * (splats.width() as f32 - 1.0)) as i32;
+                        let y = f32::floor(v * (splats.height() as f32 - 1.0)) as i32;
+                        splats.add(x, y, contribution * weight);
+                    }
+                    None => radiance += contribution * weight,
+                }
+            }
+        }
+
+        radiance
+    }
+
+    fn splats(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,130 @@
+use std::sync::OnceLock;
+
+use rand::Rng;
+
+use crate::sampler::seeded_rng;
+
+pub const MASK_SIZE: usize = 64;
+
+// Width of the gaussian that measures how clustered the points are
+const SIGMA: f32 = 1.5;
+
+// Tileable blue-noise threshold mask with values in (0, 1), generated once with
+// Ulichney's void-and-cluster method
+pub fn mask() -> &'static [f32] {
+    static MASK: OnceLock<Vec<f32>> = OnceLock::new();
+    MASK.get_or_init(void_and_cluster)
+}
+
+// Mask value at pixel (x, y), repeating the tile
+pub fn value(x: i32, y: i32) -> f32 {
+    let size = MASK_SIZE as i32;
+    mask()[(y.rem_euclid(size) * size + x.rem_euclid(size)) as usize]
+}
+
+// Energy of every pixel from a gaussian around each set point, wrapping around the tile
+#[derive(Clone)]
+struct Energy {
+    kernel: Vec<f32>,
+    values: Vec<f32>,
+}
+
+impl Energy {
+    fn new() -> Energy {
+        let mut kernel = vec![0.0; MASK_SIZE * MASK_SIZE];
+        for dy in 0..MASK_SIZE {
+            for dx in 0..MASK_SIZE {
+                let wrap = |d: usize| usize::min(d, MASK_SIZE - d) as f32;
+                let distance2 = wrap(dx) * wrap(dx) + wrap(dy) * wrap(dy);
+                kernel[dy * MASK_SIZE + dx] = f32::exp(-distance2 / (2.0 * SIGMA * SIGMA));
+            }
+        }
+        Energy {
+            kernel,
+            values: vec![0.0; MASK_SIZE * MASK_SIZE],
+        }
+    }
+
+    fn splat(&mut self, index: usize, sign: f32) {
+        let (px, py) = (index % MASK_SIZE, index / MASK_SIZE);
+        for y in 0..MASK_SIZE {
+            let dy = (y + MASK_SIZE - py) % MASK_SIZE;
+            for x in 0..MASK_SIZE {
+                let dx = (x + MASK_SIZE - px) % MASK_SIZE;
+                self.values[y * MASK_SIZE + x] += sign * self.kernel[dy * MASK_SIZE + dx];
+            }
+        }
+    }
+
+    // Set point in the densest cluster
+    fn tightest_cluster(&self, points: &[bool]) -> usize {
+        (0..points.len())
+            .filter(|&index| points[index])
+            .max_by(|&a, &b| self.values[a].total_cmp(&self.values[b]))
+            .unwrap()
+    }
+
+    // Empty pixel furthest from the set points
+    fn largest_void(&self, points: &[bool]) -> usize {
+        (0..points.len())
+            .filter(|&index| !points[index])
+            .min_by(|&a, &b| self.values[a].total_cmp(&self.values[b]))
+            .unwrap()
+    }
+}
+
+fn void_and_cluster() -> Vec<f32> {
+    let count = MASK_SIZE * MASK_SIZE;
+    let mut points = vec![false; count];
+    let mut energy = Energy::new();
+
+    // Random initial points, then moved from the tightest cluster to the largest void
+    // until they are evenly spread
+    let mut rng = seeded_rng(&[MASK_SIZE as u64]);
+    let initial = count / 10;
+    let mut placed = 0;
+    while placed < initial {
+        let index = rng.gen_range(0..count);
+        if !points[index] {
+            points[index] = true;
+            energy.splat(index, 1.0);
+            placed += 1;
+        }
+    }
+    loop {
+        let cluster = energy.tightest_cluster(&points);
+        points[cluster] = false;
+        energy.splat(cluster, -1.0);
+        let void = energy.largest_void(&points);
+        points[void] = true;
+        energy.splat(void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; count];
+
+    // The initial points are ranked by removing them from the densest regions first
+    let mut removal_points = points.clone();
+    let mut removal_energy = energy.clone();
+    for rank in (0..initial).rev() {
+        let cluster = removal_energy.tightest_cluster(&removal_points);
+        removal_points[cluster] = false;
+        removal_energy.splat(cluster, -1.0);
+        ranks[cluster] = rank;
+    }
+
+    // The rest by filling the largest voids
+    for rank in initial..count {
+        let void = energy.largest_void(&points);
+        points[void] = true;
+        energy.splat(void, 1.0);
+        ranks[void] = rank;
+    }
+
+    ranks
+        .iter()
+        .map(|&rank| (rank as f32 + 0.5) / count as f32)
+        .collect()
+}
@@ -1,4 +1,3 @@
-use rand::Rng;
 use std::cmp::Ordering;
 use std::usize;
 
@@ -39,11 +38,28 @@ fn compare_by_axis(a: &HittablePtr, b: &HittablePtr, axis: usize) -> Ordering {
         .expect("Bbox error")
 }
 
+// Axis along which the bounding boxes of `objects` spread the most
+fn longest_axis(objects: &[HittablePtr]) -> usize {
+    let bounds = objects
+        .iter()
+        .filter_map(|object| object.bounding_box())
+        .reduce(Aabb::combine)
+        .unwrap_or_else(Aabb::null_aabb);
+    let extent = bounds.diagonal();
+    if extent.x >= extent.y && extent.x >= extent.z {
+        0
+    } else if extent.y >= extent.z {
+        1
+    } else {
+        2
+    }
+}
+
 impl BvhNode {
     fn new(src_objects: &[HittablePtr], start: usize, end: usize) -> BvhNode {
         
         let mut objects = src_objects.to_vec();
-        let axis = rand::thread_rng().gen_range(0..=2);
+        let axis = longest_axis(&src_objects[start..end]);
         let comparator = move |a:&HittablePtr, b:&HittablePtr| compare_by_axis(a, b, axis);
         let span = end - start;
 
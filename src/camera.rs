@@ -1,5 +1,6 @@
 use crate::maths::Ray;
 use crate::maths::Vec3;
+use crate::sampler::Sampler;
 
 #[derive(Clone, Copy)]
 pub struct Camera {
@@ -12,6 +13,7 @@ pub struct Camera {
     w: Vec3,
     aspect_ratio: f32,
     lens_radius: f32,
+    focus_dist: f32,
 }
 
 impl Camera {
@@ -46,6 +48,7 @@ impl Camera {
             w,
             aspect_ratio,
             lens_radius: aperture / 2.0,
+            focus_dist,
         }
     }
 
@@ -53,8 +56,8 @@ impl Camera {
         self.aspect_ratio
     }
 
-    pub fn get_ray(&self, s: f32, t: f32) -> Ray {
-        let rd = Vec3::on_unit_disc() * self.lens_radius;
+    pub fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Ray {
+        let rd = Vec3::on_unit_disc(sampler) * self.lens_radius;
         let offset = self.u * rd.x + self.v * rd.y;
 
         Ray::new(
@@ -63,6 +66,35 @@ impl Camera {
         )
     }
 
+    pub fn forward(&self) -> Vec3 {
+        -self.w
+    }
+
+    pub fn sample_lens(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        let rd = Vec3::on_unit_disc(sampler) * self.lens_radius;
+        self.origin + self.u * rd.x + self.v * rd.y
+    }
+
+    // Area of the s, t in [0, 1] image at unit distance from the lens
+    pub fn film_area(&self) -> f32 {
+        self.horizontal.length() * self.vertical.length() / (self.focus_dist * self.focus_dist)
+    }
+
+    // Image coordinates (s, t) of the ray from `lens_point` through `point`,
+    // the inverse of get_ray. None for points behind the camera.
+    pub fn project(&self, lens_point: Vec3, point: Vec3) -> Option<(f32, f32)> {
+        let dir = point - lens_point;
+        let depth = Vec3::dot(dir, self.forward());
+        if depth <= 0.0 {
+            return None;
+        }
+
+        let on_focus_plane = lens_point + dir * (self.focus_dist / depth) - self.lower_left_corner;
+        let s = Vec3::dot(on_focus_plane, self.u) / self.horizontal.length();
+        let t = Vec3::dot(on_focus_plane, self.v) / self.vertical.length();
+        Some((s, t))
+    }
+
     pub fn straight_ray(&self, s: f32, t: f32) -> Ray {
         Ray::new(
             self.origin,
@@ -0,0 +1,333 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::film::Film;
+use crate::tiles;
+
+const MAGIC: &[u8; 4] = b"RTCK";
+const VERSION: u32 = 3;
+
+// Everything needed to continue a tiled render where it stopped. The samplers derive every
+// random number from the seed, the pixel and the sample index, so the index of the next
+// sample is all the random state there is.
+pub struct Checkpoint {
+    // Settings that change the image, a checkpoint only resumes a render with the same key
+    pub key: String,
+    pub passes: u32,
+    pub samples_per_pixel: u32,
+    pub film: Film,
+}
+
+impl Checkpoint {
+    pub fn new(key: String, film: Film) -> Checkpoint {
+        Checkpoint {
+            key,
+            passes: 0,
+            samples_per_pixel: 0,
+            film,
+        }
+    }
+
+    // Writes to a temporary file first so an interruption never leaves a broken checkpoint
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let temporary = format!("{}.tmp", path);
+        let mut out = BufWriter::new(File::create(&temporary)?);
+        out.write_all(MAGIC)?;
+        write_u32(&mut out, VERSION)?;
+        write_string(&mut out, &self.key)?;
+        write_u32(&mut out, self.passes)?;
+        write_u32(&mut out, self.samples_per_pixel)?;
+        write_u32(&mut out, self.film.width() as u32)?;
+        write_u32(&mut out, self.film.height() as u32)?;
+        self.film.write(&mut out)?;
+        out.into_inner()?.sync_all()?;
+        std::fs::rename(temporary, path)
+    }
+
+    pub fn load(path: &str) -> io::Result<Checkpoint> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC || read_u32(&mut input)? != VERSION {
+            return Err(invalid_data("not a checkpoint of this renderer version"));
+        }
+        let key = read_string(&mut input)?;
+        let passes = read_u32(&mut input)?;
+        let samples_per_pixel = read_u32(&mut input)?;
+        let width = read_u32(&mut input)? as i32;
+        let height = read_u32(&mut input)? as i32;
+        let tiles = tiles::spiral(width, height, tiles::TILE_SIZE);
+        Ok(Checkpoint {
+            key,
+            passes,
+            samples_per_pixel,
+            film: Film::read(&mut input, width, height, tiles)?,
+        })
+    }
+}
+
+// Adds up partial renders of the same image, e.g. rendered on several machines with
+// different seeds. Returns the merged render under the key of the first one. Renders with
+// the same seed took the same samples, so they are refused instead of counted twice.
+pub fn merge(paths: &[&str]) -> io::Result<Checkpoint> {
+    let without_seed = |key: &str| {
+        key.split(' ')
+            .filter(|arg| !arg.starts_with("-seed="))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    // Renders without -seed used the default of 0
+    let seed = |key: &str| match key.split(' ').find_map(|arg| arg.strip_prefix("-seed=")) {
+        Some(seed) => seed
+            .parse::<u64>()
+            .map_err(|_| invalid_data("invalid seed in a partial render")),
+        None => Ok(0),
+    };
+
+    let mut merged: Option<Checkpoint> = None;
+    let mut seeds = Vec::new();
+    for path in paths {
+        let partial = Checkpoint::load(path)?;
+        println!("{}: {} samples per pixel", path, partial.samples_per_pixel);
+        let partial_seed = seed(&partial.key)?;
+        if seeds.contains(&partial_seed) {
+            return Err(invalid_data("partial renders share a seed"));
+        }
+        seeds.push(partial_seed);
+        match &mut merged {
+            None => merged = Some(partial),
+            Some(merged) => {
+                if without_seed(&partial.key) != without_seed(&merged.key) {
+                    return Err(invalid_data("partial renders have different settings"));
+                }
+                merged.film.merge(&partial.film);
+                merged.passes += partial.passes;
+                merged.samples_per_pixel += partial.samples_per_pixel;
+            }
+        }
+    }
+    merged.ok_or_else(|| invalid_data("nothing to merge"))
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Turns the first SIGINT into a request to stop at the end of the current pass. A second
+// one kills the process as usual.
+pub fn catch_interrupt() {
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+pub fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub fn write_u32(out: &mut impl Write, value: u32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+pub fn write_u64(out: &mut impl Write, value: u64) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+pub fn write_f32(out: &mut impl Write, value: f32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+pub fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub fn read_f32(input: &mut impl Read) -> io::Result<f32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+pub fn write_string(out: &mut impl Write, value: &str) -> io::Result<()> {
+    write_u32(out, value.len() as u32)?;
+    out.write_all(value.as_bytes())
+}
+
+pub fn read_string(input: &mut impl Read) -> io::Result<String> {
+    let length = read_u32(input)? as usize;
+    if length > 1 << 20 {
+        return Err(invalid_data("string too long"));
+    }
+    let mut bytes = vec![0; length];
+    input.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| invalid_data("invalid string"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::film::SplatBuffer;
+
+    fn components(image: &[Color]) -> Vec<[f32; 3]> {
+        image
+            .iter()
+            .map(|color| [color.r, color.g, color.b])
+            .collect()
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let (width, height) = (21, 7);
+        let mut film = Film::new(
+            width,
+            height,
+            tiles::spiral(width, height, tiles::TILE_SIZE),
+        );
+        film.set_light_groups(vec![String::from("sun"), String::from("lamp")]);
+        for index in 0..film.tiles().len() {
+            let mut pixels = film.tile_pixels(index);
+            let mut sums = film.tile_light_sums(index);
+            for (pixel, stats) in pixels.iter_mut().enumerate() {
+                for sample in 0..=(pixel + index) % 3 {
+                    let value = (pixel * 7 + index * 3 + sample) as f32 * 0.1;
+                    stats.add(Color::new(value, 0.5 * value, 1.0 / (1.0 + value)));
+                }
+                sums[pixel * 2] = Color::new(pixel as f32, 0.25, 0.0);
+                sums[pixel * 2 + 1] = Color::new(0.0, index as f32, 0.75);
+            }
+        }
+        let mut splats = SplatBuffer::new(width, height);
+        splats.add(3, 4, Color::new(2.0, 1.0, 0.5));
+        film.add_splats(&splats);
+        film.add_samples(1234);
+        let normals = (0..width * height)
+            .map(|i| Color::new(i as f32, -1.0, f32::INFINITY))
+            .collect();
+        film.set_aov("normal", normals);
+
+        let mut checkpoint = Checkpoint::new(String::from("-s=16 -seed=3"), film);
+        checkpoint.passes = 5;
+        checkpoint.samples_per_pixel = 80;
+        let path = std::env::temp_dir().join(format!("checkpoint-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        checkpoint.save(path).unwrap();
+        let loaded = Checkpoint::load(path);
+        std::fs::remove_file(path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.key, checkpoint.key);
+        assert_eq!(loaded.passes, 5);
+        assert_eq!(loaded.samples_per_pixel, 80);
+        let (film, loaded) = (&checkpoint.film, &loaded.film);
+        assert_eq!((loaded.width(), loaded.height()), (width, height));
+        assert_eq!(loaded.samples(), 1234);
+        assert_eq!(loaded.sample_counts(), film.sample_counts());
+        assert_eq!(components(&loaded.image()), components(&film.image()));
+        for index in 0..film.tiles().len() {
+            let stats = |film: &Film| -> Vec<(u32, [f32; 3], f32)> {
+                film.tile_pixels(index)
+                    .iter()
+                    .map(|stats| {
+                        let mean = stats.mean();
+                        (
+                            stats.count(),
+                            [mean.r, mean.g, mean.b],
+                            stats.relative_error(),
+                        )
+                    })
+                    .collect()
+            };
+            assert_eq!(stats(loaded), stats(film));
+        }
+        assert_eq!(loaded.light_groups(), film.light_groups());
+        let layers = |film: &Film| -> Vec<(String, Vec<[f32; 3]>)> {
+            let mut layers = film.aovs().to_vec();
+            layers.extend(film.light_images());
+            layers
+                .iter()
+                .map(|(name, pixels)| (name.clone(), components(pixels)))
+                .collect()
+        };
+        assert_eq!(layers(loaded), layers(film));
+    }
+
+    // Saves a render whose pixels all took `samples` samples of `value`
+    fn save_partial(name: &str, key: &str, value: f32, samples: usize) -> String {
+        let mut film = Film::new(4, 4, tiles::spiral(4, 4, tiles::TILE_SIZE));
+        for index in 0..film.tiles().len() {
+            for stats in film.tile_pixels(index).iter_mut() {
+                for _ in 0..samples {
+                    stats.add(Color::new(value, value, value));
+                }
+            }
+        }
+        film.set_aov("albedo", vec![Color::new(value, 0.0, 0.0); 16]);
+        film.add_samples(16 * samples as u64);
+        let mut checkpoint = Checkpoint::new(key.to_string(), film);
+        checkpoint.passes = 1;
+        checkpoint.samples_per_pixel = samples as u32;
+        let path = std::env::temp_dir().join(format!("{}-{}.bin", name, std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        checkpoint.save(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn merge_weights_by_sample_count_and_refuses_shared_seeds() {
+        let first = save_partial("merge-first", "-s=1", 1.0, 1);
+        let second = save_partial("merge-second", "-s=1 -seed=5", 3.0, 3);
+        let same_seed = save_partial("merge-same-seed", "-seed=5 -s=1", 2.0, 1);
+        let merged = merge(&[&first, &second]);
+        let shared = merge(&[&first, &second, &same_seed]);
+        let twice = merge(&[&first, &first]);
+        for path in [first, second, same_seed] {
+            std::fs::remove_file(path).unwrap();
+        }
+
+        let merged = merged.unwrap();
+        assert_eq!(merged.key, "-s=1");
+        assert_eq!((merged.passes, merged.samples_per_pixel), (2, 4));
+        assert_eq!(merged.film.samples(), 64);
+        assert_eq!(merged.film.sample_counts(), vec![4; 16]);
+        assert_eq!(components(&merged.film.image()), vec![[2.5; 3]; 16]);
+        assert_eq!(
+            components(&merged.film.aovs()[0].1),
+            vec![[2.5, 0.0, 0.0]; 16]
+        );
+        for error in [shared.err().unwrap(), twice.err().unwrap()] {
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(error.to_string(), "partial renders share a seed");
+        }
+    }
+
+    #[test]
+    fn load_rejects_other_files() {
+        let path = std::env::temp_dir().join(format!("not-checkpoint-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, b"RTCK\x02\0\0\0").unwrap();
+        let loaded = Checkpoint::load(path);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(loaded.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+}
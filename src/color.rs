@@ -25,11 +25,22 @@ impl Color {
         Color{r, g, b}
     }
 
+    // Encodes linear values in [0, 1] with the sRGB transfer function
     pub fn as_rgb8(self: Color ) -> RGB8 {
-        let r = (256.0 * clamp(0.0, 0.9999, self.r).sqrt()) as u8;
-        let g = (256.0 * clamp(0.0, 0.9999, self.g).sqrt()) as u8;
-        let b = (256.0 * clamp(0.0, 0.9999, self.b).sqrt()) as u8;
-        RGB8 { r, g, b }
+        let encode = |value: f32| (255.0 * srgb_oetf(clamp(0.0, 1.0, value)) + 0.5) as u8;
+        RGB8 { r: encode(self.r), g: encode(self.g), b: encode(self.b) }
+    }
+
+    pub fn luminance(self) -> f32 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
+    pub fn max_component(self) -> f32 {
+        f32::max(self.r, f32::max(self.g, self.b))
+    }
+
+    pub fn is_black(self) -> bool {
+        self.r <= 0.0 && self.g <= 0.0 && self.b <= 0.0
     }
 
     pub fn lerp(a: Color, b: Color, t: f32) -> Color {
@@ -37,6 +48,15 @@ impl Color {
     }
 }
 
+// Linear segment near black, then a 1/2.4 power curve
+fn srgb_oetf(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        12.92 * value
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
 impl std::ops::Add for Color {
     type Output = Color;
 
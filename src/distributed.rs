@@ -0,0 +1,307 @@
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Range;
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::checkpoint::{invalid_data, read_string, read_u32, write_string, write_u32};
+use crate::film::Film;
+use crate::tiles;
+
+const MAGIC: &[u8; 4] = b"RTDW";
+
+// Samples still to be rendered and the merged result of the workers that finished
+struct Job {
+    samples_per_pixel: u32,
+    chunk: u32,
+    next: u32,
+    // Ranges of workers that disconnected before delivering them
+    returned: Vec<Range<u32>>,
+    // Connected workers, and how many of them are rendering a range right now
+    workers: u32,
+    rendering: u32,
+    merged_samples: u32,
+    film: Film,
+}
+
+impl Job {
+    fn next_range(&mut self) -> Option<Range<u32>> {
+        if let Some(range) = self.returned.pop() {
+            return Some(range);
+        }
+        if self.next == self.samples_per_pixel {
+            return None;
+        }
+        let begin = self.next;
+        self.next = u32::min(begin + self.chunk, self.samples_per_pixel);
+        Some(begin..self.next)
+    }
+}
+
+// Ranges a worker was handed that aren't merged yet, the last one still being rendered
+// while `rendering` is set
+#[derive(Default)]
+struct Assignment {
+    ranges: Vec<Range<u32>>,
+    rendering: bool,
+}
+
+// Listens for workers started with the same settings and hands them out ranges of
+// `chunk` samples per pixel until every sample of the image is rendered, then returns the
+// merged film, which starts out as `film`. Workers can join at any time, and the samples of
+// a worker that disconnects are given to the others. Workers that run out of samples wait
+// until nobody else is rendering, so they can pick those up. Fails when the last worker is
+// lost with samples left over.
+pub fn coordinate(
+    address: &str,
+    key: &str,
+    film: Film,
+    samples_per_pixel: u32,
+    chunk: u32,
+) -> io::Result<Film> {
+    let listener = TcpListener::bind(address)?;
+    println!("Waiting for workers on {}", listener.local_addr()?);
+
+    let (width, height) = (film.width(), film.height());
+    let job = Arc::new((
+        Mutex::new(Job {
+            samples_per_pixel,
+            chunk: chunk.max(1),
+            next: 0,
+            returned: Vec::new(),
+            workers: 0,
+            rendering: 0,
+            merged_samples: 0,
+            film,
+        }),
+        Condvar::new(),
+    ));
+
+    // Accepts workers for as long as the process lives
+    let key = key.to_string();
+    let accepting = job.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let (job, key) = (accepting.clone(), key.clone());
+            std::thread::spawn(move || {
+                let peer = stream.peer_addr().map(|peer| peer.to_string());
+                let peer = peer.unwrap_or_else(|_| String::from("unknown"));
+                let mut assignment = Assignment::default();
+                if let Err(error) = serve_worker(stream, &key, &job, &mut assignment) {
+                    println!("Lost worker {}: {}", peer, error);
+                    let mut state = job.0.lock().unwrap();
+                    state.returned.extend(assignment.ranges);
+                    state.rendering -= assignment.rendering as u32;
+                }
+                job.0.lock().unwrap().workers -= 1;
+                job.1.notify_all();
+            });
+        }
+    });
+
+    let (lock, changed) = &*job;
+    let mut state = lock.lock().unwrap();
+    while state.merged_samples < samples_per_pixel {
+        if state.workers == 0 && !state.returned.is_empty() {
+            return Err(io::Error::other(
+                "lost every worker before the image was done",
+            ));
+        }
+        state = changed.wait(state).unwrap();
+    }
+    let tiles = tiles::spiral(width, height, tiles::TILE_SIZE);
+    Ok(std::mem::replace(
+        &mut state.film,
+        Film::new(width, height, tiles),
+    ))
+}
+
+fn serve_worker(
+    stream: TcpStream,
+    key: &str,
+    job: &(Mutex<Job>, Condvar),
+    assignment: &mut Assignment,
+) -> io::Result<()> {
+    let (lock, changed) = job;
+    lock.lock().unwrap().workers += 1;
+    stream.set_nodelay(true)?;
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut out = BufWriter::new(stream);
+
+    let mut magic = [0; 4];
+    input.read_exact(&mut magic)?;
+    let worker_key = read_string(&mut input)?;
+    let accepted = &magic == MAGIC && worker_key == key;
+    write_u32(&mut out, accepted as u32)?;
+    out.flush()?;
+    if !accepted {
+        return Err(invalid_data("worker was started with different settings"));
+    }
+
+    // Each request for work also tells that the previous range is done
+    loop {
+        read_u32(&mut input)?;
+        let mut state = lock.lock().unwrap();
+        if assignment.rendering {
+            assignment.rendering = false;
+            state.rendering -= 1;
+            changed.notify_all();
+        }
+        let range = loop {
+            if let Some(range) = state.next_range() {
+                break range;
+            }
+            if state.rendering == 0 {
+                break 0..0;
+            }
+            state = changed.wait(state).unwrap();
+        };
+        if !range.is_empty() {
+            state.rendering += 1;
+            assignment.rendering = true;
+            assignment.ranges.push(range.clone());
+        }
+        drop(state);
+
+        write_u32(&mut out, range.start)?;
+        write_u32(&mut out, range.end)?;
+        out.flush()?;
+        if range.is_empty() {
+            break;
+        }
+    }
+
+    let (width, height) = {
+        let state = lock.lock().unwrap();
+        (state.film.width(), state.film.height())
+    };
+    let tiles = tiles::spiral(width, height, tiles::TILE_SIZE);
+    let film = Film::read(&mut input, width, height, tiles)?;
+
+    let mut state = lock.lock().unwrap();
+    state.film.merge(&film);
+    state.merged_samples += assignment
+        .ranges
+        .drain(..)
+        .map(|range| range.len() as u32)
+        .sum::<u32>();
+    println!(
+        "Merged samples of a worker, {}/{} samples per pixel done",
+        state.merged_samples, state.samples_per_pixel
+    );
+    changed.notify_all();
+    Ok(())
+}
+
+// Connects to a coordinator and renders the sample ranges it hands out with `render`, then
+// sends back everything that was rendered into `film`
+pub fn work(
+    address: &str,
+    key: &str,
+    mut film: Film,
+    mut render: impl FnMut(&mut Film, Range<u32>),
+) -> io::Result<()> {
+    let stream = TcpStream::connect(address)?;
+    stream.set_nodelay(true)?;
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut out = BufWriter::new(stream);
+
+    out.write_all(MAGIC)?;
+    write_string(&mut out, key)?;
+    out.flush()?;
+    if read_u32(&mut input)? == 0 {
+        return Err(invalid_data(
+            "coordinator was started with different settings",
+        ));
+    }
+
+    loop {
+        write_u32(&mut out, 0)?;
+        out.flush()?;
+        let range = read_u32(&mut input)?..read_u32(&mut input)?;
+        if range.is_empty() {
+            break;
+        }
+        println!("Rendering samples {} to {}", range.start, range.end);
+        render(&mut film, range);
+    }
+
+    film.write(&mut out)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    const KEY: &str = "test";
+
+    fn test_film() -> Film {
+        Film::new(4, 4, tiles::spiral(4, 4, tiles::TILE_SIZE))
+    }
+
+    fn free_address() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn start_coordinator(address: &str) -> std::thread::JoinHandle<io::Result<Film>> {
+        let address = address.to_string();
+        std::thread::spawn(move || coordinate(&address, KEY, test_film(), 8, 2))
+    }
+
+    // Joins like a worker, takes one range and returns the connection without rendering it
+    fn take_range(address: &str) -> TcpStream {
+        let mut stream = loop {
+            match TcpStream::connect(address) {
+                Ok(stream) => break stream,
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        stream.write_all(MAGIC).unwrap();
+        write_string(&mut stream, KEY).unwrap();
+        assert_eq!(read_u32(&mut stream).unwrap(), 1);
+        write_u32(&mut stream, 0).unwrap();
+        let range = read_u32(&mut stream).unwrap()..read_u32(&mut stream).unwrap();
+        assert!(!range.is_empty());
+        stream
+    }
+
+    #[test]
+    fn lost_range_goes_to_a_finished_worker() {
+        let address = free_address();
+        let coordinator = start_coordinator(&address);
+        let lost = take_range(&address);
+
+        let (sender, rendered) = mpsc::channel();
+        let worker = {
+            let address = address.clone();
+            std::thread::spawn(move || {
+                work(&address, KEY, test_film(), |film, range| {
+                    film.add_samples(range.len() as u64);
+                    sender.send(range).unwrap();
+                })
+            })
+        };
+
+        // The worker renders everything else, then has to wait for the lost range
+        let mut ranges: Vec<_> = (0..3).map(|_| rendered.recv().unwrap()).collect();
+        drop(lost);
+        ranges.push(rendered.recv().unwrap());
+        ranges.sort_by_key(|range| range.start);
+        assert_eq!(ranges, [0..2, 2..4, 4..6, 6..8]);
+
+        worker.join().unwrap().unwrap();
+        let film = coordinator.join().unwrap().unwrap();
+        assert_eq!(film.samples(), 8);
+    }
+
+    #[test]
+    fn losing_every_worker_fails() {
+        let address = free_address();
+        let coordinator = start_coordinator(&address);
+        drop(take_range(&address));
+        assert!(coordinator.join().unwrap().is_err());
+    }
+}
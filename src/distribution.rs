@@ -0,0 +1,105 @@
+// Piecewise-constant distributions for importance sampling tabulated functions
+
+pub struct Distribution1D {
+    func: Vec<f32>,
+    cdf: Vec<f32>,
+    integral: f32,
+}
+
+impl Distribution1D {
+    pub fn new(func: &[f32]) -> Distribution1D {
+        let count = func.len();
+        let mut cdf = vec![0.0; count + 1];
+        for i in 0..count {
+            cdf[i + 1] = cdf[i] + func[i] / count as f32;
+        }
+
+        let integral = cdf[count];
+        if integral > 0.0 {
+            for value in cdf.iter_mut() {
+                *value /= integral;
+            }
+        } else {
+            for (i, value) in cdf.iter_mut().enumerate() {
+                *value = i as f32 / count as f32;
+            }
+        }
+
+        Distribution1D {
+            func: func.to_vec(),
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    // Returns the continuous sample in [0, 1), its pdf and the segment it landed in
+    pub fn sample_continuous(&self, u: f32) -> (f32, f32, usize) {
+        let count = self.count();
+        let index = self.cdf.partition_point(|&c| c <= u).saturating_sub(1);
+        let index = usize::min(index, count - 1);
+
+        let mut du = u - self.cdf[index];
+        let width = self.cdf[index + 1] - self.cdf[index];
+        if width > 0.0 {
+            du /= width;
+        }
+
+        let x = (index as f32 + du) / count as f32;
+        (f32::min(x, 1.0 - f32::EPSILON), self.pdf(index), index)
+    }
+
+    pub fn pdf(&self, index: usize) -> f32 {
+        if self.integral > 0.0 {
+            self.func[index] / self.integral
+        } else {
+            1.0
+        }
+    }
+}
+
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    // `func` is laid out row by row, rows are sampled first then the column within the row
+    pub fn new(func: &[f32], width: usize, height: usize) -> Distribution2D {
+        let conditional: Vec<Distribution1D> = (0..height)
+            .map(|v| Distribution1D::new(&func[v * width..(v + 1) * width]))
+            .collect();
+        let row_integrals: Vec<f32> = conditional.iter().map(|row| row.integral()).collect();
+
+        Distribution2D {
+            conditional,
+            marginal: Distribution1D::new(&row_integrals),
+        }
+    }
+
+    pub fn sample_continuous(&self, u0: f32, u1: f32) -> (f32, f32, f32) {
+        let (v, pdf_v, row) = self.marginal.sample_continuous(u1);
+        let (u, pdf_u, _) = self.conditional[row].sample_continuous(u0);
+        (u, v, pdf_u * pdf_v)
+    }
+
+    pub fn pdf(&self, u: f32, v: f32) -> f32 {
+        let height = self.marginal.count();
+        let row = usize::min((v * height as f32) as usize, height - 1);
+        let width = self.conditional[row].count();
+        let column = usize::min((u * width as f32) as usize, width - 1);
+
+        let integral = self.marginal.integral();
+        if integral <= 0.0 {
+            return 1.0;
+        }
+        self.conditional[row].func[column] / integral
+    }
+}
@@ -0,0 +1,119 @@
+use std::f32::consts::PI;
+
+use crate::color::Color;
+use crate::distribution::Distribution2D;
+use crate::maths::Vec3;
+use crate::sampler::Sampler;
+
+pub type EnvironmentPtr = std::sync::Arc<dyn Environment>;
+
+// Infinitely distant light surrounding the scene, seen by rays that miss all geometry
+pub trait Environment: Send + Sync {
+    fn radiance(&self, dir: Vec3) -> Color;
+
+    fn random_direction(&self, sampler: &mut dyn Sampler) -> Vec3;
+
+    // Solid angle pdf of random_direction
+    fn pdf_value(&self, dir: Vec3) -> f32;
+}
+
+// Equirectangular (latitude-longitude) HDR map, +y is up and the top row is the zenith
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>,
+    rotation: f32,
+    intensity: f32,
+    distribution: Distribution2D,
+}
+
+impl EnvironmentMap {
+    pub fn load(path: &str, rotation: f32, intensity: f32) -> Option<EnvironmentMap> {
+        let mut file = std::fs::File::open(path).ok()?;
+        let (info, data) =
+            stb::image::stbi_loadf_from_reader(&mut file, stb::image::Channels::Rgb)?;
+
+        let pixels = data
+            .as_slice()
+            .chunks(3)
+            .map(|rgb| Color::new(rgb[0], rgb[1], rgb[2]))
+            .collect();
+
+        Some(EnvironmentMap::new(
+            pixels,
+            info.width as usize,
+            info.height as usize,
+            rotation,
+            intensity,
+        ))
+    }
+
+    // `rotation` turns the map around the up axis, in degrees
+    pub fn new(
+        pixels: Vec<Color>,
+        width: usize,
+        height: usize,
+        rotation: f32,
+        intensity: f32,
+    ) -> EnvironmentMap {
+        let mut weights = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let sin_theta = f32::sin(PI * (y as f32 + 0.5) / height as f32);
+            for x in 0..width {
+                weights.push(pixels[y * width + x].luminance() * sin_theta);
+            }
+        }
+
+        EnvironmentMap {
+            width,
+            height,
+            pixels,
+            rotation: f32::to_radians(rotation),
+            intensity,
+            distribution: Distribution2D::new(&weights, width, height),
+        }
+    }
+
+    fn to_uv(&self, dir: Vec3) -> (f32, f32) {
+        let dir = dir.normalized();
+        let phi = f32::atan2(dir.z, dir.x) - self.rotation;
+        let theta = f32::acos(crate::maths::clamp(-1.0, 1.0, dir.y));
+        let u = (phi / (2.0 * PI)).rem_euclid(1.0);
+        (u, theta / PI)
+    }
+
+    fn direction_at(&self, u: f32, v: f32) -> Vec3 {
+        let phi = u * 2.0 * PI + self.rotation;
+        let theta = v * PI;
+        Vec3::new(
+            theta.sin() * phi.cos(),
+            theta.cos(),
+            theta.sin() * phi.sin(),
+        )
+    }
+}
+
+impl Environment for EnvironmentMap {
+    fn radiance(&self, dir: Vec3) -> Color {
+        let (u, v) = self.to_uv(dir);
+        let x = usize::min((u * self.width as f32) as usize, self.width - 1);
+        let y = usize::min((v * self.height as f32) as usize, self.height - 1);
+        self.pixels[y * self.width + x] * self.intensity
+    }
+
+    fn random_direction(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        let (u, v, _) = self
+            .distribution
+            .sample_continuous(sampler.next_1d(), sampler.next_1d());
+        self.direction_at(u, v)
+    }
+
+    fn pdf_value(&self, dir: Vec3) -> f32 {
+        let (u, v) = self.to_uv(dir);
+        let sin_theta = f32::sin(PI * v);
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(u, v) / (2.0 * PI * PI * sin_theta)
+    }
+}
@@ -0,0 +1,403 @@
+use std::io::{self, Read, Write};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::checkpoint::{
+    invalid_data, read_f32, read_string, read_u32, read_u64, write_f32, write_string, write_u32,
+    write_u64,
+};
+use crate::color::{self, Color};
+use crate::tiles::Tile;
+
+// Radiance deposited onto arbitrary pixels, e.g. by light tracing. Splats are kept in the
+// order they were made, so adding up the buffers of all tiles in tile order gives the same
+// image whichever threads rendered them.
+pub struct SplatBuffer {
+    width: i32,
+    height: i32,
+    splats: Vec<(u32, Color)>,
+}
+
+impl SplatBuffer {
+    pub fn new(width: i32, height: i32) -> SplatBuffer {
+        SplatBuffer {
+            width,
+            height,
+            splats: Vec::new(),
+        }
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn add(&mut self, x: i32, y: i32, color: Color) {
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            return;
+        }
+        self.splats.push(((y * self.width + x) as u32, color));
+    }
+
+    pub fn add_to(&self, image: &mut [Color], scale: f32) {
+        for &(pixel, splat) in &self.splats {
+            image[pixel as usize] += splat * scale;
+        }
+    }
+}
+
+// Running mean of a pixel's samples and the variance of their luminance (Welford)
+#[derive(Clone)]
+pub struct PixelStats {
+    count: u32,
+    mean: Color,
+    luminance_mean: f32,
+    luminance_m2: f32,
+}
+
+impl PixelStats {
+    pub fn new() -> PixelStats {
+        PixelStats {
+            count: 0,
+            mean: color::BLACK,
+            luminance_mean: 0.0,
+            luminance_m2: 0.0,
+        }
+    }
+
+    pub fn add(&mut self, sample: Color) {
+        self.count += 1;
+        let weight = 1.0 / self.count as f32;
+        self.mean = self.mean * (1.0 - weight) + sample * weight;
+
+        let luminance = sample.luminance();
+        let delta = luminance - self.luminance_mean;
+        self.luminance_mean += delta * weight;
+        self.luminance_m2 += delta * (luminance - self.luminance_mean);
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Color {
+        self.mean
+    }
+
+    // Standard error of the mean luminance relative to the mean. Dark pixels are measured
+    // against a floor so that they converge instead of chasing tiny absolute errors.
+    pub fn relative_error(&self) -> f32 {
+        if self.count < 2 {
+            return f32::INFINITY;
+        }
+        let variance = self.luminance_m2 / (self.count - 1) as f32;
+        let standard_error = f32::sqrt(variance / self.count as f32);
+        standard_error / f32::max(self.luminance_mean, 0.01)
+    }
+
+    // Combines the samples of two disjoint sets of samples of the same pixel
+    pub fn merge(&mut self, other: &PixelStats) {
+        let count = self.count + other.count;
+        if count == 0 {
+            return;
+        }
+        let weight = other.count as f32 / count as f32;
+        let delta = other.luminance_mean - self.luminance_mean;
+        self.mean = self.mean * (1.0 - weight) + other.mean * weight;
+        self.luminance_mean += delta * weight;
+        self.luminance_m2 += other.luminance_m2 + delta * delta * self.count as f32 * weight;
+        self.count = count;
+    }
+
+    fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        write_u32(out, self.count)?;
+        write_color(out, self.mean)?;
+        write_f32(out, self.luminance_mean)?;
+        write_f32(out, self.luminance_m2)
+    }
+
+    fn read(input: &mut impl Read) -> io::Result<PixelStats> {
+        Ok(PixelStats {
+            count: read_u32(input)?,
+            mean: read_color(input)?,
+            luminance_mean: read_f32(input)?,
+            luminance_m2: read_f32(input)?,
+        })
+    }
+}
+
+// Accumulated samples of a whole render, kept between progressive passes. Pixels are
+// stored per tile so worker threads only ever lock the tile they are rendering.
+pub struct Film {
+    width: i32,
+    height: i32,
+    tiles: Vec<Tile>,
+    pixels: Vec<Mutex<Vec<PixelStats>>>,
+    splats: Vec<Color>,
+    samples: u64,
+    // Named images like normals or albedo in image order, each pixel the average over the
+    // pixel's samples so films merge them weighted by sample count
+    aovs: Vec<(String, Vec<Color>)>,
+    // Sums of what each light group contributed to the samples of a pixel, per tile with
+    // the groups of a pixel next to each other
+    light_groups: Vec<String>,
+    light_sums: Vec<Mutex<Vec<Color>>>,
+}
+
+impl Film {
+    pub fn new(width: i32, height: i32, tiles: Vec<Tile>) -> Film {
+        let pixels = tiles
+            .iter()
+            .map(|tile| Mutex::new(vec![PixelStats::new(); tile.pixel_count()]))
+            .collect();
+        let light_sums = tiles.iter().map(|_| Mutex::new(Vec::new())).collect();
+        Film {
+            width,
+            height,
+            tiles,
+            pixels,
+            splats: vec![color::BLACK; (width * height) as usize],
+            samples: 0,
+            aovs: Vec::new(),
+            light_groups: Vec::new(),
+            light_sums,
+        }
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn tiles(&self) -> &[Tile] {
+        &self.tiles
+    }
+
+    // Row-major pixels of the tile at index
+    pub fn tile_pixels(&self, index: usize) -> MutexGuard<'_, Vec<PixelStats>> {
+        self.pixels[index].lock().unwrap()
+    }
+
+    // Row-major light group sums of the tile at index, light_groups().len() per pixel
+    pub fn tile_light_sums(&self, index: usize) -> MutexGuard<'_, Vec<Color>> {
+        self.light_sums[index].lock().unwrap()
+    }
+
+    pub fn light_groups(&self) -> &[String] {
+        &self.light_groups
+    }
+
+    // Starts recording the contribution of every light group, clearing earlier sums
+    pub fn set_light_groups(&mut self, names: Vec<String>) {
+        for (tile, sums) in self.tiles.iter().zip(self.light_sums.iter_mut()) {
+            *sums.get_mut().unwrap() = vec![color::BLACK; tile.pixel_count() * names.len()];
+        }
+        self.light_groups = names;
+    }
+
+    // Average contribution of every light group to each pixel, named light_<group>
+    pub fn light_images(&self) -> Vec<(String, Vec<Color>)> {
+        let groups = self.light_groups.len();
+        let mut images = vec![vec![color::BLACK; (self.width * self.height) as usize]; groups];
+        for (index, tile) in self.tiles.iter().enumerate() {
+            let pixels = self.pixels[index].lock().unwrap();
+            let sums = self.light_sums[index].lock().unwrap();
+            for (group, image) in images.iter_mut().enumerate() {
+                let means: Vec<Color> = pixels
+                    .iter()
+                    .enumerate()
+                    .map(|(pixel, stats)| {
+                        sums[pixel * groups + group] * (1.0 / stats.count().max(1) as f32)
+                    })
+                    .collect();
+                tile.write_to(&means, image, self.width);
+            }
+        }
+        let names = self
+            .light_groups
+            .iter()
+            .map(|name| format!("light_{}", name));
+        names.zip(images).collect()
+    }
+
+    pub fn add_splats(&mut self, splats: &SplatBuffer) {
+        splats.add_to(&mut self.splats, 1.0);
+    }
+
+    pub fn add_samples(&mut self, samples: u64) {
+        self.samples += samples;
+    }
+
+    // Camera samples taken so far over the whole image
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    pub fn image(&self) -> Vec<Color> {
+        let mut image = vec![color::BLACK; (self.width * self.height) as usize];
+        for (tile, pixels) in self.tiles.iter().zip(self.pixels.iter()) {
+            let means: Vec<Color> = pixels.lock().unwrap().iter().map(PixelStats::mean).collect();
+            tile.write_to(&means, &mut image, self.width);
+        }
+
+        // Every camera sample may splat, so splats average over all samples of the image
+        let scale = image.len() as f32 / self.samples.max(1) as f32;
+        for (pixel, splat) in image.iter_mut().zip(self.splats.iter()) {
+            *pixel += *splat * scale;
+        }
+        image
+    }
+    pub fn aovs(&self) -> &[(String, Vec<Color>)] {
+        &self.aovs
+    }
+
+    pub fn set_aov(&mut self, name: &str, pixels: Vec<Color>) {
+        match self.aovs.iter_mut().find(|(aov, _)| aov == name) {
+            Some((_, aov_pixels)) => *aov_pixels = pixels,
+            None => self.aovs.push((name.to_string(), pixels)),
+        }
+    }
+
+    // Samples taken of every pixel, in image order
+    pub fn sample_counts(&self) -> Vec<u32> {
+        let mut counts = vec![0; (self.width * self.height) as usize];
+        for (tile, pixels) in self.tiles.iter().zip(self.pixels.iter()) {
+            let pixels = pixels.lock().unwrap();
+            let tile_counts: Vec<u32> = pixels.iter().map(PixelStats::count).collect();
+            tile.write_to(&tile_counts, &mut counts, self.width);
+        }
+        counts
+    }
+
+    // Adds the samples of a film rendered with different samples of the same image
+    pub fn merge(&mut self, other: &Film) {
+        let counts = self.sample_counts();
+        let other_counts = other.sample_counts();
+        for (name, other_pixels) in &other.aovs {
+            match self.aovs.iter_mut().find(|(aov, _)| aov == name) {
+                Some((_, pixels)) => {
+                    for (index, pixel) in pixels.iter_mut().enumerate() {
+                        // Keeps values like an infinite depth intact when one side is empty
+                        if counts[index] == 0 {
+                            *pixel = other_pixels[index];
+                        } else if other_counts[index] > 0 {
+                            let count = counts[index] + other_counts[index];
+                            let weight = other_counts[index] as f32 / count as f32;
+                            *pixel = *pixel * (1.0 - weight) + other_pixels[index] * weight;
+                        }
+                    }
+                }
+                None => self.aovs.push((name.clone(), other_pixels.clone())),
+            }
+        }
+
+        for (pixels, other_pixels) in self.pixels.iter_mut().zip(other.pixels.iter()) {
+            let other_pixels = other_pixels.lock().unwrap();
+            let pixels = pixels.get_mut().unwrap();
+            for (stats, other_stats) in pixels.iter_mut().zip(other_pixels.iter()) {
+                stats.merge(other_stats);
+            }
+        }
+        if self.light_groups == other.light_groups {
+            for (sums, other_sums) in self.light_sums.iter_mut().zip(other.light_sums.iter()) {
+                let other_sums = other_sums.lock().unwrap();
+                for (sum, other_sum) in sums.get_mut().unwrap().iter_mut().zip(other_sums.iter()) {
+                    *sum += *other_sum;
+                }
+            }
+        }
+        for (splat, other_splat) in self.splats.iter_mut().zip(other.splats.iter()) {
+            *splat += *other_splat;
+        }
+        self.samples += other.samples;
+    }
+
+    // Raw sums of all samples, bit for bit, so a resumed render continues exactly
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        write_u32(out, self.pixels.len() as u32)?;
+        for pixels in &self.pixels {
+            let pixels = pixels.lock().unwrap();
+            write_u32(out, pixels.len() as u32)?;
+            for stats in pixels.iter() {
+                stats.write(out)?;
+            }
+        }
+        for &splat in &self.splats {
+            write_color(out, splat)?;
+        }
+        write_u64(out, self.samples)?;
+        write_u32(out, self.aovs.len() as u32)?;
+        for (name, pixels) in &self.aovs {
+            write_string(out, name)?;
+            for &pixel in pixels {
+                write_color(out, pixel)?;
+            }
+        }
+        write_u32(out, self.light_groups.len() as u32)?;
+        for name in &self.light_groups {
+            write_string(out, name)?;
+        }
+        for sums in &self.light_sums {
+            for &sum in sums.lock().unwrap().iter() {
+                write_color(out, sum)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read(
+        input: &mut impl Read,
+        width: i32,
+        height: i32,
+        tiles: Vec<Tile>,
+    ) -> io::Result<Film> {
+        let mut film = Film::new(width, height, tiles);
+        if read_u32(input)? as usize != film.tiles.len() {
+            return Err(invalid_data("tiles don't match the image size"));
+        }
+        for (tile, pixels) in film.tiles.iter().zip(film.pixels.iter_mut()) {
+            if read_u32(input)? as usize != tile.pixel_count() {
+                return Err(invalid_data("tiles don't match the image size"));
+            }
+            for stats in pixels.get_mut().unwrap().iter_mut() {
+                *stats = PixelStats::read(input)?;
+            }
+        }
+        for splat in film.splats.iter_mut() {
+            *splat = read_color(input)?;
+        }
+        film.samples = read_u64(input)?;
+        for _ in 0..read_u32(input)? {
+            let name = read_string(input)?;
+            let pixels = (0..width * height)
+                .map(|_| read_color(input))
+                .collect::<io::Result<_>>()?;
+            film.aovs.push((name, pixels));
+        }
+        let light_groups = (0..read_u32(input)?)
+            .map(|_| read_string(input))
+            .collect::<io::Result<_>>()?;
+        film.set_light_groups(light_groups);
+        for sums in film.light_sums.iter_mut() {
+            for sum in sums.get_mut().unwrap().iter_mut() {
+                *sum = read_color(input)?;
+            }
+        }
+        Ok(film)
+    }
+}
+
+fn write_color(out: &mut impl Write, color: Color) -> io::Result<()> {
+    write_f32(out, color.r)?;
+    write_f32(out, color.g)?;
+    write_f32(out, color.b)
+}
+
+fn read_color(input: &mut impl Read) -> io::Result<Color> {
+    Ok(Color::new(read_f32(input)?, read_f32(input)?, read_f32(input)?))
+}
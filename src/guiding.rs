@@ -0,0 +1,672 @@
+use core::f32;
+use std::f32::consts::PI;
+
+use crate::aabb::Aabb;
+use crate::camera::Camera;
+use crate::color::{Color, BLACK, WHITE};
+use crate::hittable::Hittable;
+use crate::integrator::{emitted_radiance, miss_radiance, sample_lights_mis, ROULETTE_START_DEPTH};
+use crate::maths::{Ray, Vec3};
+use crate::sampler::{hash, IndependentSampler, Sampler};
+use crate::scene::Scene;
+
+// Path guiding with an SD-tree, after Müller et al. "Practical Path Guiding for Efficient
+// Light-Transport Simulation" (2017). A binary tree over space holds a quadtree over
+// directions in each leaf. Training passes with doubling sample counts record incident
+// radiance into the tree while sampling from the one the previous pass learned, the
+// remaining budget is rendered with the final tree.
+pub struct GuidingSettings {
+    pub samples_per_pixel: i32,
+    pub max_depth: i32,
+    pub num_threads: i32,
+    pub seed: u64,
+}
+
+// Probability of sampling the BSDF instead of the guiding distribution
+const BSDF_SAMPLING_FRACTION: f32 = 0.5;
+// A spatial leaf splits once it recorded more than this times sqrt(2^pass) samples
+const SPATIAL_THRESHOLD: f32 = 12000.0;
+// A directional node subdivides once it holds more than this fraction of the energy
+const ENERGY_THRESHOLD: f32 = 0.01;
+const MAX_DIRECTIONAL_DEPTH: u32 = 20;
+// Rows of the image rendered and recorded together
+const CHUNK_ROWS: usize = 8;
+
+// Quadtree node over a square of the cylindrical direction parameterization. A child
+// index of 0 marks a leaf quadrant, the root can never be a child.
+#[derive(Clone)]
+struct QuadNode {
+    sums: [f32; 4],
+    children: [u32; 4],
+}
+
+impl QuadNode {
+    fn new(sums: [f32; 4]) -> QuadNode {
+        QuadNode {
+            sums,
+            children: [0; 4],
+        }
+    }
+
+    fn sums(&self) -> [f32; 4] {
+        self.sums
+    }
+
+    // Quadrant holding `p` and `p` remapped into it
+    fn quadrant(p: (f32, f32)) -> (usize, (f32, f32)) {
+        let x = p.0 >= 0.5;
+        let y = p.1 >= 0.5;
+        let quadrant = x as usize + 2 * y as usize;
+        let p = (
+            if x { 2.0 * p.0 - 1.0 } else { 2.0 * p.0 },
+            if y { 2.0 * p.1 - 1.0 } else { 2.0 * p.1 },
+        );
+        (quadrant, p)
+    }
+}
+
+// Directional distribution of one spatial leaf
+#[derive(Clone)]
+struct DTree {
+    nodes: Vec<QuadNode>,
+    samples: u32,
+}
+
+impl DTree {
+    fn new() -> DTree {
+        DTree {
+            nodes: vec![QuadNode::new([0.0; 4])],
+            samples: 0,
+        }
+    }
+
+    fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    // Same subdivision without anything recorded, for recording separately and adding later
+    fn cleared(&self) -> DTree {
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|node| QuadNode {
+                sums: [0.0; 4],
+                children: node.children,
+            })
+            .collect();
+        DTree { nodes, samples: 0 }
+    }
+
+    // Adds what another copy of this tree recorded
+    fn add(&mut self, other: &DTree) {
+        for (node, other) in self.nodes.iter_mut().zip(other.nodes.iter()) {
+            for (sum, other) in node.sums.iter_mut().zip(other.sums.iter()) {
+                *sum += other;
+            }
+        }
+        self.samples += other.samples;
+    }
+
+    fn total(&self) -> f32 {
+        self.nodes[0].sums().iter().sum()
+    }
+
+    fn record(&mut self, dir: Vec3, radiance: f32) {
+        let mut p = dir_to_square(dir);
+        let mut node = 0;
+        loop {
+            let (quadrant, inner) = QuadNode::quadrant(p);
+            self.nodes[node].sums[quadrant] += radiance;
+            match self.nodes[node].children[quadrant] {
+                0 => break,
+                child => node = child as usize,
+            }
+            p = inner;
+        }
+        self.samples += 1;
+    }
+
+    fn sample(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        let mut origin = (0.0, 0.0);
+        let mut size = 1.0;
+        let mut node = 0;
+        loop {
+            let sums = self.nodes[node].sums();
+            let total: f32 = sums.iter().sum();
+            let mut u = sampler.next_1d() * total;
+            let mut quadrant = 3;
+            for (index, &sum) in sums.iter().enumerate() {
+                if u < sum {
+                    quadrant = index;
+                    break;
+                }
+                u -= sum;
+            }
+
+            size *= 0.5;
+            origin.0 += size * (quadrant % 2) as f32;
+            origin.1 += size * (quadrant / 2) as f32;
+            match self.nodes[node].children[quadrant] {
+                0 => break,
+                child => node = child as usize,
+            }
+        }
+
+        let p = (
+            origin.0 + size * sampler.next_1d(),
+            origin.1 + size * sampler.next_1d(),
+        );
+        square_to_dir(p)
+    }
+
+    // Solid angle density of sample()
+    fn pdf(&self, dir: Vec3) -> f32 {
+        let mut p = dir_to_square(dir);
+        let mut node = 0;
+        let mut pdf = 1.0;
+        loop {
+            let sums = self.nodes[node].sums();
+            let total: f32 = sums.iter().sum();
+            let (quadrant, inner) = QuadNode::quadrant(p);
+            if total <= 0.0 || sums[quadrant] <= 0.0 {
+                return 0.0;
+            }
+            pdf *= 4.0 * sums[quadrant] / total;
+            match self.nodes[node].children[quadrant] {
+                0 => break,
+                child => node = child as usize,
+            }
+            p = inner;
+        }
+        pdf / (4.0 * PI)
+    }
+
+    // Empty tree subdivided where this one recorded a large share of the energy
+    fn refined(&self) -> DTree {
+        let mut tree = DTree::new();
+        let total = self.total();
+        if total > 0.0 {
+            tree.nodes.clear();
+            self.refine_node(&mut tree.nodes, Some(0), self.nodes[0].sums(), total, 1);
+        }
+        tree
+    }
+
+    fn refine_node(
+        &self,
+        nodes: &mut Vec<QuadNode>,
+        old: Option<usize>,
+        sums: [f32; 4],
+        total: f32,
+        depth: u32,
+    ) -> u32 {
+        let index = nodes.len();
+        nodes.push(QuadNode::new([0.0; 4]));
+
+        for (quadrant, &sum) in sums.iter().enumerate() {
+            if depth >= MAX_DIRECTIONAL_DEPTH || sum <= total * ENERGY_THRESHOLD {
+                continue;
+            }
+            // Quadrants that were leaves split their energy evenly
+            let old_child = old
+                .map(|node| self.nodes[node].children[quadrant])
+                .filter(|&child| child != 0)
+                .map(|child| child as usize);
+            let child_sums = match old_child {
+                Some(child) => self.nodes[child].sums(),
+                None => [sum * 0.25; 4],
+            };
+            let child = self.refine_node(nodes, old_child, child_sums, total, depth + 1);
+            nodes[index].children[quadrant] = child;
+        }
+        index as u32
+    }
+}
+
+// Area preserving map from the unit sphere to the unit square
+fn dir_to_square(dir: Vec3) -> (f32, f32) {
+    let dir = dir.normalized();
+    let cos_theta = dir.y.clamp(-1.0, 1.0);
+    let phi = f32::atan2(dir.z, dir.x).rem_euclid(2.0 * PI);
+    (
+        ((cos_theta + 1.0) * 0.5).clamp(0.0, 0.999_999),
+        (phi / (2.0 * PI)).clamp(0.0, 0.999_999),
+    )
+}
+
+fn square_to_dir(p: (f32, f32)) -> Vec3 {
+    let cos_theta = 2.0 * p.0 - 1.0;
+    let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta * cos_theta));
+    let phi = 2.0 * PI * p.1;
+    Vec3::new(sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin())
+}
+
+// Binary tree over space, splitting along alternating axes. A child index of 0 marks a
+// leaf, whose directional trees are at `leaf`.
+struct SpatialNode {
+    axis: usize,
+    children: [u32; 2],
+    leaf: usize,
+}
+
+struct SdTree {
+    bounds: Aabb,
+    nodes: Vec<SpatialNode>,
+    // Learned by the previous pass
+    sampling: Vec<DTree>,
+    // Recorded during the current pass. Every chunk of rows records into a copy of its own,
+    // and the copies are added up in image order so the tree depends on nothing but the seed.
+    building: Vec<DTree>,
+}
+
+impl SdTree {
+    fn new(bounds: Aabb) -> SdTree {
+        // A cube keeps the cells of alternating splits close to cubes
+        let extent = bounds.max - bounds.min;
+        let size = f32::max(extent.x, f32::max(extent.y, extent.z));
+        SdTree {
+            bounds: Aabb::new(bounds.min, bounds.min + Vec3::from_scalar(size)),
+            nodes: vec![SpatialNode {
+                axis: 0,
+                children: [0; 2],
+                leaf: 0,
+            }],
+            sampling: vec![DTree::new()],
+            building: vec![DTree::new()],
+        }
+    }
+
+    fn leaf(&self, point: Vec3) -> usize {
+        let mut min = self.bounds.min;
+        let mut max = self.bounds.max;
+        let mut node = &self.nodes[0];
+        while node.children[0] != 0 {
+            let axis = node.axis;
+            let mid = 0.5 * (min[axis] + max[axis]);
+            let upper = point[axis] >= mid;
+            if upper {
+                set_axis(&mut min, axis, mid);
+            } else {
+                set_axis(&mut max, axis, mid);
+            }
+            node = &self.nodes[node.children[upper as usize] as usize];
+        }
+        node.leaf
+    }
+
+    // Splits crowded leaves and turns what this pass recorded into the sampling trees
+    fn refine(&mut self, pass: i32) {
+        let threshold = SPATIAL_THRESHOLD * f32::sqrt(f32::powi(2.0, pass));
+        let mut index = 0;
+        while index < self.nodes.len() {
+            let leaf = self.nodes[index].leaf;
+            let samples = self.building[leaf].samples() as f32;
+            if self.nodes[index].children[0] == 0 && samples > threshold {
+                let axis = self.nodes[index].axis;
+                let mut children = [0; 2];
+                for (side, child) in children.iter_mut().enumerate() {
+                    let tree = if side == 0 {
+                        leaf
+                    } else {
+                        self.sampling.push(self.sampling[leaf].clone());
+                        self.building.push(self.building[leaf].clone());
+                        self.building.len() - 1
+                    };
+                    // Each child continues with half the parent's samples
+                    self.building[tree].samples = (samples * 0.5) as u32;
+                    *child = self.nodes.len() as u32;
+                    self.nodes.push(SpatialNode {
+                        axis: (axis + 1) % 3,
+                        children: [0; 2],
+                        leaf: tree,
+                    });
+                }
+                self.nodes[index].children = children;
+            }
+            index += 1;
+        }
+
+        for (sampling, building) in self.sampling.iter_mut().zip(self.building.iter_mut()) {
+            *sampling = building.clone();
+            *building = sampling.refined();
+        }
+    }
+}
+
+fn set_axis(v: &mut Vec3, axis: usize, value: f32) {
+    match axis {
+        0 => v.x = value,
+        1 => v.y = value,
+        _ => v.z = value,
+    }
+}
+
+// Vertex whose continuation direction was sampled, for recording once the path ends
+struct GuidedVertex {
+    leaf: usize,
+    dir: Vec3,
+    // Throughput after the bounce and the radiance collected before it
+    throughput: Color,
+    radiance: Color,
+}
+
+// Records the incident radiance at each guided vertex into `recording` when given, which
+// holds the building trees of the tree's leaves
+fn trace_path(
+    ray: Ray,
+    scene: &Scene,
+    max_depth: i32,
+    tree: &SdTree,
+    recording: Option<&mut [DTree]>,
+    sampler: &mut dyn Sampler,
+) -> Color {
+    let mut radiance = BLACK;
+    let mut throughput = WHITE;
+    let mut ray = ray;
+    let mut bsdf_pdf: Option<f32> = None;
+    let mut vertices = Vec::new();
+    let learn = recording.is_some();
+
+    for depth in 0..max_depth {
+        let hit = match scene.world.hit(0.01, f32::INFINITY, &ray) {
+            Some(hit) => hit,
+            None => {
+                radiance += throughput * miss_radiance(ray, scene, bsdf_pdf);
+                break;
+            }
+        };
+
+        let mat = &hit.material;
+        radiance += throughput * emitted_radiance(ray, &hit, scene, bsdf_pdf);
+
+        let (attenuation, scattered) = match mat.scatter(ray, &hit, sampler) {
+            Some(scatter) => scatter,
+            None => break,
+        };
+
+        let (weight, scattered) = if mat.bsdf(&ray, &hit, scattered.dir).is_some() {
+            // One sample from the mixture of the BSDF and the learned distribution
+            let leaf = tree.leaf(hit.point);
+            let guide = &tree.sampling[leaf];
+            let bsdf_fraction = if guide.total() > 0.0 {
+                BSDF_SAMPLING_FRACTION
+            } else {
+                1.0
+            };
+            let mixture_pdf = |dir: Vec3| {
+                let bsdf_pdf = mat.bsdf(&ray, &hit, dir).map_or(0.0, |(_, pdf)| pdf);
+                if bsdf_fraction < 1.0 {
+                    bsdf_fraction * bsdf_pdf + (1.0 - bsdf_fraction) * guide.pdf(dir)
+                } else {
+                    bsdf_pdf
+                }
+            };
+            radiance += throughput * sample_lights_mis(ray, &hit, scene, &mixture_pdf, sampler);
+
+            let scattered = if sampler.next_1d() < bsdf_fraction {
+                scattered
+            } else {
+                Ray::new(hit.point, guide.sample(sampler))
+            };
+
+            let (f, pdf) = match mat.bsdf(&ray, &hit, scattered.dir) {
+                Some((f, _)) => (f, mixture_pdf(scattered.dir)),
+                None => break,
+            };
+            if pdf <= 0.0 || f.is_black() {
+                break;
+            }
+
+            bsdf_pdf = Some(pdf);
+            if learn {
+                vertices.push(GuidedVertex {
+                    leaf,
+                    dir: scattered.dir,
+                    throughput: throughput * f * (1.0 / pdf),
+                    radiance,
+                });
+            }
+            (f * (1.0 / pdf), scattered)
+        } else {
+            bsdf_pdf = None;
+            (attenuation, scattered)
+        };
+
+        throughput = throughput * weight;
+        ray = scattered;
+
+        if depth >= ROULETTE_START_DEPTH {
+            let survival = f32::min(throughput.max_component(), 0.95);
+            if survival <= 0.0 || sampler.next_1d() >= survival {
+                break;
+            }
+            throughput = throughput * (1.0 / survival);
+        }
+    }
+
+    // Radiance that arrived along each sampled direction, undoing the throughput up to it
+    let recording = match recording {
+        Some(recording) => recording,
+        None => return radiance,
+    };
+    for vertex in vertices {
+        let arrived = radiance + vertex.radiance * -1.0;
+        let divide = |value: f32, by: f32| if by > 0.0 { value / by } else { 0.0 };
+        let incident = Color::new(
+            divide(arrived.r, vertex.throughput.r),
+            divide(arrived.g, vertex.throughput.g),
+            divide(arrived.b, vertex.throughput.b),
+        );
+        let value = incident.luminance();
+        if value.is_finite() && value > 0.0 {
+            recording[vertex.leaf].record(vertex.dir, value);
+        }
+    }
+
+    radiance
+}
+
+struct Renderer<'a> {
+    scene: &'a Scene,
+    camera: Camera,
+    width: i32,
+    height: i32,
+    settings: &'a GuidingSettings,
+}
+
+impl Renderer<'_> {
+    fn render_pass(
+        &self,
+        tree: &mut SdTree,
+        pass: u64,
+        samples_per_pixel: i32,
+        learn: bool,
+    ) -> Vec<Color> {
+        let width = self.width as usize;
+        let mut image = vec![BLACK; width * self.height as usize];
+        let num_threads = usize::max(self.settings.num_threads as usize, 1);
+        let sampler =
+            IndependentSampler::new(samples_per_pixel as u32, hash(&[self.settings.seed, pass]));
+
+        // Chunks run in waves of one per thread, which keeps no more recordings around than
+        // there are threads. Each wave's recordings are added in chunk order.
+        let mut chunks: Vec<_> = image.chunks_mut(CHUNK_ROWS * width).enumerate().collect();
+        for wave in chunks.chunks_mut(num_threads) {
+            let sampling = &*tree;
+            let recorded: Vec<Option<Vec<DTree>>> = std::thread::scope(|threads| {
+                let handles: Vec<_> = wave
+                    .iter_mut()
+                    .map(|(chunk, pixels)| {
+                        let begin = *chunk * CHUNK_ROWS * width;
+                        let mut sampler = sampler.clone();
+                        let mut recording =
+                            learn.then(|| sampling.building.iter().map(DTree::cleared).collect());
+                        threads.spawn(move || {
+                            let chunk_recording = recording.as_deref_mut();
+                            self.render_chunk(
+                                pixels,
+                                begin,
+                                &mut sampler,
+                                sampling,
+                                chunk_recording,
+                            );
+                            recording
+                        })
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap())
+                    .collect()
+            });
+
+            for recording in recorded.into_iter().flatten() {
+                for (building, recorded) in tree.building.iter_mut().zip(recording.iter()) {
+                    building.add(recorded);
+                }
+            }
+        }
+        image
+    }
+
+    // Renders the pixels starting at index `begin` of the image
+    fn render_chunk(
+        &self,
+        pixels: &mut [Color],
+        begin: usize,
+        sampler: &mut dyn Sampler,
+        tree: &SdTree,
+        mut recording: Option<&mut [DTree]>,
+    ) {
+        let (width, height) = (self.width, self.height);
+        let samples_per_pixel = sampler.samples_per_pixel();
+        let scale = 1.0 / samples_per_pixel as f32;
+        for (offset, pixel) in pixels.iter_mut().enumerate() {
+            let index = begin + offset;
+            let (x, y) = (
+                (index % width as usize) as i32,
+                (index / width as usize) as i32,
+            );
+            let mut accum_color = BLACK;
+            for sample_index in 0..samples_per_pixel {
+                sampler.start_pixel_sample(x, y, sample_index);
+                let u = (x as f32 + sampler.next_1d()) / (width as f32 - 1.0);
+                let v = (y as f32 + sampler.next_1d()) / (height as f32 - 1.0);
+                let ray = self.camera.get_ray(u, v, sampler);
+                let max_depth = self.settings.max_depth;
+                let recording = recording.as_deref_mut();
+                accum_color += trace_path(ray, self.scene, max_depth, tree, recording, sampler);
+            }
+            *pixel = accum_color * scale;
+        }
+    }
+}
+
+pub fn render(
+    scene: &Scene,
+    camera: Camera,
+    width: i32,
+    height: i32,
+    settings: &GuidingSettings,
+) -> Vec<Color> {
+    let renderer = Renderer {
+        scene,
+        camera,
+        width,
+        height,
+        settings,
+    };
+    let bounds = scene
+        .world
+        .bounding_box()
+        .unwrap_or_else(Aabb::null_aabb)
+        .padded(0.01);
+    let mut tree = SdTree::new(bounds);
+
+    // Training passes of 1, 2, 4, ... samples while at least as many remain for the final one
+    let mut remaining = settings.samples_per_pixel;
+    let mut pass_samples = 1;
+    let mut pass = 0;
+    while 2 * pass_samples <= remaining - pass_samples {
+        renderer.render_pass(&mut tree, pass as u64, pass_samples, true);
+        tree.refine(pass);
+        remaining -= pass_samples;
+        pass_samples *= 2;
+        pass += 1;
+    }
+
+    renderer.render_pass(&mut tree, pass as u64, remaining.max(1), false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh::Bvh;
+    use crate::hittable::{HittableList, Sphere};
+    use crate::light::Lights;
+    use crate::material::{DiffuseLight, Lambertian};
+    use crate::scene::Background;
+
+    // Sums and children of every node of a directional tree
+    type Nodes = (Vec<[f32; 4]>, Vec<[u32; 4]>);
+
+    // Directional trees learned by two training passes
+    fn train(num_threads: i32) -> Vec<Nodes> {
+        let mut world = HittableList::new();
+        let grey = Lambertian::create(Color::new(0.5, 0.5, 0.5));
+        world.add(Sphere::create(0.0, -1000.0, 0.0, 1000.0, grey.clone()));
+        world.add(Sphere::create(0.0, 0.5, 0.0, 0.5, grey));
+        let lamp = DiffuseLight::create(Color::new(4.0, 4.0, 4.0));
+        world.add(Sphere::create(1.0, 2.0, 1.0, 0.3, lamp));
+        let lights = Lights::from_list(&world);
+        let scene = Scene {
+            world: Bvh::new(world),
+            lights,
+            background: Background::Constant(Color::new(0.1, 0.1, 0.1)),
+        };
+        let camera = Camera::create(
+            Vec3::new(3.0, 1.5, 3.0),
+            Vec3::new(0.0, 0.5, 0.0),
+            Vec3::up(),
+            1.5,
+            40.0,
+            0.0,
+            4.0,
+        );
+        let settings = GuidingSettings {
+            samples_per_pixel: 8,
+            max_depth: 8,
+            num_threads,
+            seed: 7,
+        };
+        let renderer = Renderer {
+            scene: &scene,
+            camera,
+            width: 30,
+            height: 20,
+            settings: &settings,
+        };
+
+        let bounds = scene.world.bounding_box().unwrap().padded(0.01);
+        let mut tree = SdTree::new(bounds);
+        for pass in 0..2 {
+            renderer.render_pass(&mut tree, pass as u64, 1 << pass, true);
+            tree.refine(pass);
+        }
+        tree.sampling
+            .iter()
+            .map(|dtree| {
+                let sums = dtree.nodes.iter().map(|node| node.sums).collect();
+                let children = dtree.nodes.iter().map(|node| node.children).collect();
+                (sums, children)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tree_does_not_depend_on_the_thread_count() {
+        let tree = train(1);
+        assert!(tree[0].0.len() > 1, "the tree never subdivided");
+        assert_eq!(train(4), tree);
+    }
+}
@@ -1,19 +1,35 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 use crate::aabb::Aabb;
 use crate::material::MaterialPtr;
-use crate::maths::Ray;
-use crate::maths::Vec3;
+use crate::maths::{Onb, Ray, Vec3};
+use crate::sampler::Sampler;
 
 pub type HittablePtr = std::sync::Arc<dyn Hittable>;
+
+static NEXT_OBJECT_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub fn next_object_id() -> usize {
+    NEXT_OBJECT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
 pub struct HitRecord {
     pub point: Vec3,
     pub normal: Vec3,
     pub material: MaterialPtr,
     pub t: f32,
     pub front_face: bool,
+    pub object_id: usize,
 }
 
 impl HitRecord {
-    fn create(ray: &Ray, t: f32, material: MaterialPtr, outward_normal: Vec3) -> HitRecord {
+    fn create(
+        ray: &Ray,
+        t: f32,
+        material: MaterialPtr,
+        outward_normal: Vec3,
+        object_id: usize,
+    ) -> HitRecord {
         let point = ray.at(t);
         let front_face = Vec3::dot(ray.dir, outward_normal) < 0.0;
         let normal = match front_face {
@@ -26,6 +42,7 @@ impl HitRecord {
             material,
             t,
             front_face,
+            object_id,
         }
     }
 }
@@ -33,11 +50,43 @@ pub trait Hittable: Send + Sync {
     fn hit(&self, tmin: f32, tmax: f32, ray: &Ray) -> Option<HitRecord>;
 
     fn bounding_box(&self) -> Option<Aabb>;
+
+    fn material(&self) -> Option<MaterialPtr> {
+        None
+    }
+
+    fn pdf_value(&self, _origin: Vec3, _dir: Vec3) -> f32 {
+        0.0
+    }
+
+    fn random_direction(&self, _origin: Vec3, _sampler: &mut dyn Sampler) -> Vec3 {
+        Vec3::up()
+    }
+
+    fn object_id(&self) -> Option<usize> {
+        None
+    }
+
+    fn area(&self) -> f32 {
+        0.0
+    }
+
+    // Uniformly distributed point on the surface and its outward normal
+    fn sample_surface(&self, _sampler: &mut dyn Sampler) -> Option<(Vec3, Vec3)> {
+        None
+    }
+
+    // Cone around `axis` containing every surface normal, as its axis and cosine of its
+    // half angle. Curved objects cover the whole sphere.
+    fn normal_bounds(&self) -> (Vec3, f32) {
+        (Vec3::up(), -1.0)
+    }
 }
 pub struct Sphere {
     pub center: Vec3,
     pub radius: f32,
     pub material: MaterialPtr,
+    id: usize,
 }
 
 impl Sphere {
@@ -46,8 +95,17 @@ impl Sphere {
             center: Vec3 { x, y, z },
             radius,
             material,
+            id: next_object_id(),
         })
     }
+
+    // 1 - cos of the half angle the sphere subtends, without cancellation for small or
+    // distant spheres
+    fn one_minus_cos_theta_max(&self, distance2: f32) -> f32 {
+        let sin2_theta_max = f32::min(self.radius * self.radius / distance2, 1.0);
+        let cos_theta_max = f32::sqrt(1.0 - sin2_theta_max);
+        sin2_theta_max / (1.0 + cos_theta_max)
+    }
 }
 
 impl Hittable for Sphere {
@@ -78,6 +136,7 @@ impl Hittable for Sphere {
             root,
             self.material.clone(),
             outward_normal,
+            self.id,
         ))
     }
 
@@ -85,6 +144,265 @@ impl Hittable for Sphere {
         let radius3 = Vec3::from_scalar(self.radius);
         Some(Aabb::new(self.center - radius3, self.center + radius3))
     }
+
+    fn material(&self) -> Option<MaterialPtr> {
+        Some(self.material.clone())
+    }
+
+    fn pdf_value(&self, origin: Vec3, dir: Vec3) -> f32 {
+        let distance2 = (self.center - origin).length2();
+        if distance2 <= self.radius * self.radius {
+            return 0.0;
+        }
+
+        if self.hit(0.001, f32::INFINITY, &Ray::new(origin, dir)).is_none() {
+            return 0.0;
+        }
+
+        let solid_angle = 2.0 * std::f32::consts::PI * self.one_minus_cos_theta_max(distance2);
+        1.0 / solid_angle
+    }
+
+    fn random_direction(&self, origin: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let direction = self.center - origin;
+        let distance2 = direction.length2();
+        let r1 = sampler.next_1d();
+        let r2 = sampler.next_1d();
+
+        let z = 1.0 - r2 * self.one_minus_cos_theta_max(distance2);
+        let phi = 2.0 * std::f32::consts::PI * r1;
+        let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - z * z));
+        let local = Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, z);
+
+        Onb::from_w(direction).local(local)
+    }
+
+    fn object_id(&self) -> Option<usize> {
+        Some(self.id)
+    }
+
+    fn area(&self) -> f32 {
+        4.0 * std::f32::consts::PI * self.radius * self.radius
+    }
+
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(Vec3, Vec3)> {
+        let normal = Vec3::random_unit_vector(sampler);
+        Some((self.center + normal * self.radius, normal))
+    }
+}
+
+pub struct Quad {
+    pub corner: Vec3,
+    pub u: Vec3,
+    pub v: Vec3,
+    pub material: MaterialPtr,
+    normal: Vec3,
+    w: Vec3,
+    area: f32,
+    id: usize,
+}
+
+impl Quad {
+    pub fn create(corner: Vec3, u: Vec3, v: Vec3, material: MaterialPtr) -> HittablePtr {
+        let n = Vec3::cross(u, v);
+        std::sync::Arc::new(Quad {
+            corner,
+            u,
+            v,
+            material,
+            normal: n.normalized(),
+            w: n / n.length2(),
+            area: n.length(),
+            id: next_object_id(),
+        })
+    }
+}
+
+impl Hittable for Quad {
+    fn hit(&self, tmin: f32, tmax: f32, ray: &Ray) -> Option<HitRecord> {
+        let denom = Vec3::dot(self.normal, ray.dir);
+        if f32::abs(denom) < 1e-8 {
+            return None;
+        }
+
+        let t = Vec3::dot(self.normal, self.corner - ray.origin) / denom;
+        if t < tmin || tmax < t {
+            return None;
+        }
+
+        let planar = ray.at(t) - self.corner;
+        let alpha = Vec3::dot(self.w, Vec3::cross(planar, self.v));
+        let beta = Vec3::dot(self.w, Vec3::cross(self.u, planar));
+        if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
+            return None;
+        }
+
+        Some(HitRecord::create(
+            ray,
+            t,
+            self.material.clone(),
+            self.normal,
+            self.id,
+        ))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let a = self.corner;
+        let b = self.corner + self.u + self.v;
+        let bbox = Aabb::new(a, a)
+            .combine(Aabb::new(b, b))
+            .combine(Aabb::new(a + self.u, a + self.u))
+            .combine(Aabb::new(a + self.v, a + self.v));
+        Some(bbox.padded(0.0001))
+    }
+
+    fn material(&self) -> Option<MaterialPtr> {
+        Some(self.material.clone())
+    }
+
+    fn pdf_value(&self, origin: Vec3, dir: Vec3) -> f32 {
+        area_pdf(self, self.area, origin, dir)
+    }
+
+    fn random_direction(&self, origin: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let point = self.corner + self.u * sampler.next_1d() + self.v * sampler.next_1d();
+        point - origin
+    }
+
+    fn object_id(&self) -> Option<usize> {
+        Some(self.id)
+    }
+
+    fn area(&self) -> f32 {
+        self.area
+    }
+
+    fn normal_bounds(&self) -> (Vec3, f32) {
+        (self.normal, 1.0)
+    }
+
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(Vec3, Vec3)> {
+        let point = self.corner + self.u * sampler.next_1d() + self.v * sampler.next_1d();
+        Some((point, self.normal))
+    }
+}
+
+pub struct Triangle {
+    pub v0: Vec3,
+    pub v1: Vec3,
+    pub v2: Vec3,
+    pub material: MaterialPtr,
+    normal: Vec3,
+    area: f32,
+    id: usize,
+}
+
+impl Triangle {
+    pub fn create(v0: Vec3, v1: Vec3, v2: Vec3, material: MaterialPtr) -> HittablePtr {
+        let n = Vec3::cross(v1 - v0, v2 - v0);
+        std::sync::Arc::new(Triangle {
+            v0,
+            v1,
+            v2,
+            material,
+            normal: n.normalized(),
+            area: 0.5 * n.length(),
+            id: next_object_id(),
+        })
+    }
+
+    fn random_point(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        let su = f32::sqrt(sampler.next_1d());
+        let b1 = 1.0 - su;
+        let b2 = sampler.next_1d() * su;
+        self.v0 * (1.0 - b1 - b2) + self.v1 * b1 + self.v2 * b2
+    }
+}
+
+impl Hittable for Triangle {
+    fn hit(&self, tmin: f32, tmax: f32, ray: &Ray) -> Option<HitRecord> {
+        let edge1 = self.v1 - self.v0;
+        let edge2 = self.v2 - self.v0;
+        let pvec = Vec3::cross(ray.dir, edge2);
+        let det = Vec3::dot(edge1, pvec);
+        if f32::abs(det) < 1e-8 {
+            return None;
+        }
+
+        let inv_det = 1.0 / det;
+        let tvec = ray.origin - self.v0;
+        let b1 = Vec3::dot(tvec, pvec) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+
+        let qvec = Vec3::cross(tvec, edge1);
+        let b2 = Vec3::dot(ray.dir, qvec) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+
+        let t = Vec3::dot(edge2, qvec) * inv_det;
+        if t < tmin || tmax < t {
+            return None;
+        }
+
+        Some(HitRecord::create(
+            ray,
+            t,
+            self.material.clone(),
+            self.normal,
+            self.id,
+        ))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let bbox = Aabb::new(self.v0, self.v0)
+            .combine(Aabb::new(self.v1, self.v1))
+            .combine(Aabb::new(self.v2, self.v2));
+        Some(bbox.padded(0.0001))
+    }
+
+    fn material(&self) -> Option<MaterialPtr> {
+        Some(self.material.clone())
+    }
+
+    fn pdf_value(&self, origin: Vec3, dir: Vec3) -> f32 {
+        area_pdf(self, self.area, origin, dir)
+    }
+
+    fn random_direction(&self, origin: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        self.random_point(sampler) - origin
+    }
+
+    fn object_id(&self) -> Option<usize> {
+        Some(self.id)
+    }
+
+    fn area(&self) -> f32 {
+        self.area
+    }
+
+    fn normal_bounds(&self) -> (Vec3, f32) {
+        (self.normal, 1.0)
+    }
+
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(Vec3, Vec3)> {
+        Some((self.random_point(sampler), self.normal))
+    }
+}
+
+fn area_pdf<T: Hittable>(shape: &T, area: f32, origin: Vec3, dir: Vec3) -> f32 {
+    let dir = dir.normalized();
+    if let Some(hit) = shape.hit(0.001, f32::INFINITY, &Ray::new(origin, dir)) {
+        let cosine = f32::abs(Vec3::dot(dir, hit.normal));
+        if cosine < 1e-6 {
+            return 0.0;
+        }
+        return hit.t * hit.t / (cosine * area);
+    }
+
+    0.0
 }
 
 pub struct HittableList {
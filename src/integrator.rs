@@ -0,0 +1,570 @@
+use core::f32;
+
+use crate::bdpt::Bdpt;
+use crate::camera::Camera;
+use crate::color::{Color, BLACK, WHITE};
+use crate::film::SplatBuffer;
+use crate::hittable::{HitRecord, Hittable};
+use crate::light::{LightSample, LightTarget};
+use crate::maths::{Onb, Ray, Vec3};
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::spectral::SpectralPathTracer;
+
+pub type IntegratorPtr = std::sync::Arc<dyn Integrator>;
+
+pub trait Integrator: Send + Sync {
+    // Radiance along a camera ray, drawing every random number from `sampler`. Light that
+    // reaches other pixels goes into `splats`.
+    fn radiance(
+        &self,
+        ray: Ray,
+        scene: &Scene,
+        splats: &mut SplatBuffer,
+        sampler: &mut dyn Sampler,
+    ) -> Color;
+
+    // Whether radiance ever adds to `splats`
+    fn splats(&self) -> bool {
+        false
+    }
+
+    // Whether radiance_by_light splits the radiance up by light group
+    fn separates_lights(&self) -> bool {
+        false
+    }
+
+    // Radiance along a camera ray, also adding what every light group of the scene
+    // contributed to `groups`
+    fn radiance_by_light(
+        &self,
+        ray: Ray,
+        scene: &Scene,
+        splats: &mut SplatBuffer,
+        _groups: &mut [Color],
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        self.radiance(ray, scene, splats, sampler)
+    }
+}
+
+// Adds radiance to the light group it came from, if the caller records light groups at all
+fn record(groups: &mut [Color], group: Option<usize>, radiance: Color) {
+    if let Some(total) = group.and_then(|group| groups.get_mut(group)) {
+        *total += radiance;
+    }
+}
+
+pub fn create(
+    name: &str,
+    max_depth: i32,
+    camera: Camera,
+    width: i32,
+    height: i32,
+) -> Option<IntegratorPtr> {
+    let integrator: IntegratorPtr = match name {
+        "path" => std::sync::Arc::new(PathTracer { max_depth }),
+        "bdpt" => std::sync::Arc::new(Bdpt {
+            max_depth,
+            camera,
+            width,
+            height,
+        }),
+        "spectral" => std::sync::Arc::new(SpectralPathTracer { max_depth }),
+        "direct" => std::sync::Arc::new(DirectLighting { max_depth }),
+        "ao" => std::sync::Arc::new(AmbientOcclusion {
+            samples: 4,
+            distance: 1.0,
+        }),
+        "normal" => std::sync::Arc::new(DebugView::Normal),
+        "albedo" => std::sync::Arc::new(DebugView::Albedo),
+        "depth" => std::sync::Arc::new(DebugView::Depth),
+        _ => return None,
+    };
+    Some(integrator)
+}
+
+pub struct PathTracer {
+    pub max_depth: i32,
+}
+
+impl Integrator for PathTracer {
+    fn radiance(
+        &self,
+        ray: Ray,
+        scene: &Scene,
+        _: &mut SplatBuffer,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        trace_path(ray, scene, self.max_depth, &mut [], sampler)
+    }
+
+    fn separates_lights(&self) -> bool {
+        true
+    }
+
+    fn radiance_by_light(
+        &self,
+        ray: Ray,
+        scene: &Scene,
+        _: &mut SplatBuffer,
+        groups: &mut [Color],
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        trace_path(ray, scene, self.max_depth, groups, sampler)
+    }
+}
+
+// Light sampling at the first diffuse hit only, specular bounces are followed up to max_depth
+pub struct DirectLighting {
+    pub max_depth: i32,
+}
+
+impl Integrator for DirectLighting {
+    fn radiance(
+        &self,
+        ray: Ray,
+        scene: &Scene,
+        _: &mut SplatBuffer,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        self.direct_lighting(ray, scene, &mut [], sampler)
+    }
+
+    fn separates_lights(&self) -> bool {
+        true
+    }
+
+    fn radiance_by_light(
+        &self,
+        ray: Ray,
+        scene: &Scene,
+        _: &mut SplatBuffer,
+        groups: &mut [Color],
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        self.direct_lighting(ray, scene, groups, sampler)
+    }
+}
+
+impl DirectLighting {
+    fn direct_lighting(
+        &self,
+        ray: Ray,
+        scene: &Scene,
+        groups: &mut [Color],
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let mut radiance = BLACK;
+        let mut throughput = WHITE;
+        let mut ray = ray;
+
+        for _ in 0..self.max_depth {
+            let hit = match scene.world.hit(0.01, f32::INFINITY, &ray) {
+                Some(hit) => hit,
+                None => {
+                    let background = throughput * miss_radiance(ray, scene, None);
+                    record(groups, Some(scene.lights.background_group()), background);
+                    return radiance + background;
+                }
+            };
+
+            let emitted = throughput * hit.material.emitted();
+            record(groups, scene.lights.emitter_group(hit.object_id), emitted);
+            radiance += emitted;
+            let (attenuation, scattered) = match hit.material.scatter(ray, &hit, sampler) {
+                Some(scatter) => scatter,
+                None => break,
+            };
+
+            if hit.material.bsdf(&ray, &hit, scattered.dir).is_some() {
+                let direct =
+                    estimate_direct_by_light(ray, &hit, scene, throughput, groups, sampler);
+                return radiance + throughput * direct;
+            }
+
+            throughput = throughput * attenuation;
+            ray = scattered;
+        }
+
+        radiance
+    }
+}
+
+// Fraction of cosine weighted rays that escape within `distance` of the first hit
+pub struct AmbientOcclusion {
+    pub samples: i32,
+    pub distance: f32,
+}
+
+impl Integrator for AmbientOcclusion {
+    fn radiance(
+        &self,
+        ray: Ray,
+        scene: &Scene,
+        _: &mut SplatBuffer,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let hit = match scene.world.hit(0.01, f32::INFINITY, &ray) {
+            Some(hit) => hit,
+            None => return WHITE,
+        };
+
+        let frame = Onb::from_w(hit.normal);
+        let mut visible = 0;
+        for _ in 0..self.samples {
+            let r1 = sampler.next_1d();
+            let r2 = sampler.next_1d();
+            let phi = 2.0 * std::f32::consts::PI * r1;
+            let radius = r2.sqrt();
+            let local = Vec3::new(phi.cos() * radius, phi.sin() * radius, f32::sqrt(1.0 - r2));
+
+            let occlusion_ray = Ray::new(hit.point, frame.local(local));
+            if scene
+                .world
+                .hit(0.01, self.distance, &occlusion_ray)
+                .is_none()
+            {
+                visible += 1;
+            }
+        }
+
+        WHITE * (visible as f32 / self.samples as f32)
+    }
+}
+
+pub enum DebugView {
+    Normal,
+    Albedo,
+    Depth,
+}
+
+impl Integrator for DebugView {
+    fn radiance(&self, ray: Ray, scene: &Scene, _: &mut SplatBuffer, _: &mut dyn Sampler) -> Color {
+        let hit = match scene.world.hit(0.01, f32::INFINITY, &ray) {
+            Some(hit) => hit,
+            None => return BLACK,
+        };
+
+        match self {
+            DebugView::Normal => Color::from_vec3(hit.normal * 0.5 + Vec3::from_scalar(0.5)),
+            DebugView::Albedo => hit.material.albedo(),
+            DebugView::Depth => {
+                let distance = hit.t * ray.dir.length();
+                WHITE * (1.0 / (1.0 + distance))
+            }
+        }
+    }
+}
+
+// Bounces that always continue before Russian roulette may terminate a path
+pub(crate) const ROULETTE_START_DEPTH: i32 = 3;
+
+pub fn power_heuristic(pdf_a: f32, pdf_b: f32) -> f32 {
+    let a2 = pdf_a * pdf_a;
+    let b2 = pdf_b * pdf_b;
+    if a2 + b2 <= 0.0 {
+        return 0.0;
+    }
+    a2 / (a2 + b2)
+}
+
+// Adds what every light group contributed to `groups`, when it isn't empty
+fn trace_path(
+    ray: Ray,
+    scene: &Scene,
+    max_depth: i32,
+    groups: &mut [Color],
+    sampler: &mut dyn Sampler,
+) -> Color {
+    let mut radiance = BLACK;
+    let mut throughput = WHITE;
+    let mut ray = ray;
+    // Pdf the previous bounce sampled `ray` with, used to weight emission against
+    // light sampling. None for camera rays and specular bounces.
+    let mut bsdf_pdf: Option<f32> = None;
+
+    for depth in 0..max_depth {
+        let hit = match scene.world.hit(0.01, f32::INFINITY, &ray) {
+            Some(hit) => hit,
+            None => {
+                let background = throughput * miss_radiance(ray, scene, bsdf_pdf);
+                record(groups, Some(scene.lights.background_group()), background);
+                radiance += background;
+                break;
+            }
+        };
+
+        let mat = &hit.material;
+        let emitted = throughput * emitted_radiance(ray, &hit, scene, bsdf_pdf);
+        record(groups, scene.lights.emitter_group(hit.object_id), emitted);
+        radiance += emitted;
+
+        let (attenuation, scattered) = match mat.scatter(ray, &hit, sampler) {
+            Some(scatter) => scatter,
+            None => break,
+        };
+
+        bsdf_pdf = match mat.bsdf(&ray, &hit, scattered.dir) {
+            Some((_, scattered_pdf)) => {
+                let scatter_pdf =
+                    |dir| hit.material.bsdf(&ray, &hit, dir).map_or(0.0, |(_, pdf)| pdf);
+                let direct = sample_lights_by_light(
+                    ray,
+                    &hit,
+                    scene,
+                    &scatter_pdf,
+                    throughput,
+                    groups,
+                    sampler,
+                );
+                radiance += throughput * direct;
+                Some(scattered_pdf)
+            }
+            None => None,
+        };
+
+        throughput = throughput * attenuation;
+        ray = scattered;
+
+        if depth >= ROULETTE_START_DEPTH {
+            let survival = f32::min(throughput.max_component(), 0.95);
+            if survival <= 0.0 || sampler.next_1d() >= survival {
+                break;
+            }
+            throughput = throughput * (1.0 / survival);
+        }
+    }
+
+    radiance
+}
+
+// Emission seen by `ray`, MIS weighted when the ray was sampled from a BSDF with `bsdf_pdf`
+pub fn emitted_radiance(ray: Ray, hit: &HitRecord, scene: &Scene, bsdf_pdf: Option<f32>) -> Color {
+    let emitted = hit.material.emitted();
+    match bsdf_pdf {
+        Some(pdf) if !emitted.is_black() => {
+            let light_pdf = scene.lights.emitter_pdf(ray.origin, ray.dir, hit.object_id);
+            emitted * power_heuristic(pdf, light_pdf)
+        }
+        _ => emitted,
+    }
+}
+
+pub fn miss_radiance(ray: Ray, scene: &Scene, bsdf_pdf: Option<f32>) -> Color {
+    let background = scene.background.radiance(ray.dir);
+    match bsdf_pdf {
+        Some(pdf) if scene.lights.has_environment() => {
+            let light_pdf = scene.lights.environment_pdf(ray.dir);
+            background * power_heuristic(pdf, light_pdf)
+        }
+        _ => background,
+    }
+}
+
+// Light sampling combined with one BSDF sample that only counts what it hits directly,
+// for estimators that stop at the first diffuse surface
+pub fn estimate_direct(
+    ray: Ray,
+    hit: &HitRecord,
+    scene: &Scene,
+    sampler: &mut dyn Sampler,
+) -> Color {
+    estimate_direct_by_light(ray, hit, scene, WHITE, &mut [], sampler)
+}
+
+// estimate_direct that also adds each light's part, scaled by `weight`, to `groups`
+fn estimate_direct_by_light(
+    ray: Ray,
+    hit: &HitRecord,
+    scene: &Scene,
+    weight: Color,
+    groups: &mut [Color],
+    sampler: &mut dyn Sampler,
+) -> Color {
+    let scatter_pdf = |dir| hit.material.bsdf(&ray, hit, dir).map_or(0.0, |(_, pdf)| pdf);
+    let mut direct =
+        sample_lights_by_light(ray, hit, scene, &scatter_pdf, weight, groups, sampler);
+
+    if let Some((attenuation, scattered)) = hit.material.scatter(ray, hit, sampler) {
+        if let Some((_, pdf)) = hit.material.bsdf(&ray, hit, scattered.dir) {
+            let bsdf_pdf = Some(pdf);
+            let (radiance, group) = match scene.world.hit(0.01, f32::INFINITY, &scattered) {
+                Some(light_hit) => (
+                    emitted_radiance(scattered, &light_hit, scene, bsdf_pdf),
+                    scene.lights.emitter_group(light_hit.object_id),
+                ),
+                None => (
+                    miss_radiance(scattered, scene, bsdf_pdf),
+                    Some(scene.lights.background_group()),
+                ),
+            };
+            record(groups, group, weight * attenuation * radiance);
+            direct += attenuation * radiance;
+        }
+    }
+
+    direct
+}
+
+pub fn sample_lights(
+    ray: Ray,
+    hit: &HitRecord,
+    scene: &Scene,
+    sampler: &mut dyn Sampler,
+) -> Color {
+    let scatter_pdf = |dir| hit.material.bsdf(&ray, hit, dir).map_or(0.0, |(_, pdf)| pdf);
+    sample_lights_mis(ray, hit, scene, &scatter_pdf, sampler)
+}
+
+// Light sampling MIS weighted against a path continuing with the density `scatter_pdf`,
+// for estimators that do not scatter by the BSDF alone
+pub fn sample_lights_mis(
+    ray: Ray,
+    hit: &HitRecord,
+    scene: &Scene,
+    scatter_pdf: &dyn Fn(Vec3) -> f32,
+    sampler: &mut dyn Sampler,
+) -> Color {
+    sample_lights_by_light(ray, hit, scene, scatter_pdf, WHITE, &mut [], sampler)
+}
+
+// sample_lights_mis that also adds each light's part, scaled by `weight`, to `groups`
+fn sample_lights_by_light(
+    ray: Ray,
+    hit: &HitRecord,
+    scene: &Scene,
+    scatter_pdf: &dyn Fn(Vec3) -> f32,
+    weight: Color,
+    groups: &mut [Color],
+    sampler: &mut dyn Sampler,
+) -> Color {
+    let bsdf = |dir| {
+        let f = hit.material.bsdf(&ray, hit, dir).map(|(f, _)| f);
+        f.filter(|f| !f.is_black())
+    };
+    let mut direct = BLACK;
+    let mut add = |f: Color, radiance: Color, group| {
+        record(groups, group, weight * radiance * f);
+        direct += radiance * f;
+    };
+    sample_light_radiance(hit.point, scene, &bsdf, scatter_pdf, &mut add, sampler);
+    direct
+}
+
+// Samples every analytic light and one emitter or the environment as seen from `point`, for
+// BSDFs of any kind. `bsdf` gives the BSDF towards a light, None when it reflects nothing
+// that way. `add` gets that value for each sample with the radiance that arrives, divided
+// by the sample's pdf and MIS weighted against `scatter_pdf`, and the sample's light group.
+pub(crate) fn sample_light_radiance<F>(
+    point: Vec3,
+    scene: &Scene,
+    bsdf: &dyn Fn(Vec3) -> Option<F>,
+    scatter_pdf: &dyn Fn(Vec3) -> f32,
+    add: &mut dyn FnMut(F, Color, Option<usize>),
+    sampler: &mut dyn Sampler,
+) {
+    for (index, light) in scene.lights.analytic().iter().enumerate() {
+        if let Some(sample) = light.sample(point, sampler) {
+            if let Some(f) = bsdf(sample.dir) {
+                let shadow = Ray::new(point, sample.dir);
+                if scene.world.hit(0.01, sample.distance, &shadow).is_none() {
+                    add(f, sample.weight, Some(scene.lights.analytic_group(index)));
+                }
+            }
+        }
+    }
+
+    if !scene.lights.is_sampleable() {
+        return;
+    }
+    let sample = match scene.lights.sample_direction(point, sampler) {
+        Some(sample) if sample.pdf > 0.0 => sample,
+        _ => return,
+    };
+    let f = match bsdf(sample.dir) {
+        Some(f) => f,
+        None => return,
+    };
+
+    let radiance = light_sample_radiance(scene, point, &sample)
+        * (power_heuristic(sample.pdf, scatter_pdf(sample.dir)) / sample.pdf);
+    let group = match sample.target {
+        LightTarget::Emitter(id) => scene.lights.emitter_group(id),
+        LightTarget::Environment => Some(scene.lights.background_group()),
+    };
+    add(f, radiance, group);
+}
+
+// Radiance arriving at `point` from the light `sample` was aimed at, black when anything
+// else is in the way
+pub fn light_sample_radiance(scene: &Scene, point: Vec3, sample: &LightSample) -> Color {
+    let shadow = Ray::new(point, sample.dir);
+    match (scene.world.hit(0.01, f32::INFINITY, &shadow), sample.target) {
+        (Some(light_hit), LightTarget::Emitter(id)) if light_hit.object_id == id => {
+            light_hit.material.emitted()
+        }
+        (None, LightTarget::Environment) => scene.background.radiance(sample.dir),
+        _ => BLACK,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh::Bvh;
+    use crate::hittable::{HittableList, Sphere};
+    use crate::light::{AnalyticLight, Lights};
+    use crate::material::Lambertian;
+    use crate::sampler::IndependentSampler;
+    use crate::scene::Background;
+
+    // Direct lighting at the top of a large grey ball lit by nothing but `light`
+    fn render_lit_ground(light: AnalyticLight) -> Color {
+        let mut world = HittableList::new();
+        let grey = Lambertian::create(Color::new(0.5, 0.5, 0.5));
+        world.add(Sphere::create(0.0, -1000.0, 0.0, 1000.0, grey));
+        let mut lights = Lights::from_list(&world);
+        lights.add_analytic(light);
+        let scene = Scene {
+            world: Bvh::new(world),
+            lights,
+            background: Background::Constant(BLACK),
+        };
+
+        let ray = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let mut splats = SplatBuffer::new(1, 1);
+        let mut sampler = IndependentSampler::new(1, 0);
+        sampler.start_pixel_sample(0, 0, 0);
+        DirectLighting { max_depth: 4 }.radiance(ray, &scene, &mut splats, &mut sampler)
+    }
+
+    fn assert_close(color: Color, expected: f32) {
+        for value in [color.r, color.g, color.b] {
+            assert!((value - expected).abs() < 1e-3 * expected, "{} != {}", value, expected);
+        }
+    }
+
+    // Each light gives unit irradiance at the top of the ball, which reflects albedo / pi
+    #[test]
+    fn point_light() {
+        let light = AnalyticLight::point(Vec3::new(0.0, 1.0, 0.0), WHITE);
+        assert_close(render_lit_ground(light), 0.5 / std::f32::consts::PI);
+    }
+
+    #[test]
+    fn spot_light() {
+        let position = Vec3::new(0.0, 1.0, 0.0);
+        let light = AnalyticLight::spot(position, Vec3::zero(), WHITE, 20.0, 40.0);
+        assert_close(render_lit_ground(light), 0.5 / std::f32::consts::PI);
+
+        let away = AnalyticLight::spot(position, Vec3::new(5.0, 1.0, 0.0), WHITE, 20.0, 40.0);
+        assert!(render_lit_ground(away).is_black());
+    }
+
+    #[test]
+    fn directional_light() {
+        let light = AnalyticLight::directional(Vec3::new(0.0, 1.0, 0.0), WHITE, 0.53);
+        assert_close(render_lit_ground(light), 0.5 / std::f32::consts::PI);
+    }
+}
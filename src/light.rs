@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::color::Color;
+use crate::environment::EnvironmentPtr;
+use crate::hittable::{HittableList, HittablePtr};
+use crate::light_tree::LightTree;
+use crate::maths::{Onb, Ray, Vec3};
+use crate::sampler::Sampler;
+
+// Lights without geometry. They are never hit by rays and only reach the image through
+// direct sampling, so their sample weight already includes the pdf.
+#[derive(Clone, Copy)]
+pub enum AnalyticLight {
+    Point {
+        position: Vec3,
+        intensity: Color,
+    },
+    Spot {
+        position: Vec3,
+        direction: Vec3,
+        intensity: Color,
+        cos_inner: f32,
+        cos_outer: f32,
+    },
+    Directional {
+        to_light: Vec3,
+        irradiance: Color,
+        cos_half_angle: f32,
+    },
+}
+
+pub struct AnalyticSample {
+    pub dir: Vec3,
+    pub distance: f32,
+    pub weight: Color,
+}
+
+impl AnalyticLight {
+    pub fn point(position: Vec3, intensity: Color) -> AnalyticLight {
+        AnalyticLight::Point {
+            position,
+            intensity,
+        }
+    }
+
+    // Cone angles are the full opening angles in degrees, falloff is smooth between them
+    pub fn spot(
+        position: Vec3,
+        target: Vec3,
+        intensity: Color,
+        inner_angle: f32,
+        outer_angle: f32,
+    ) -> AnalyticLight {
+        AnalyticLight::Spot {
+            position,
+            direction: (target - position).normalized(),
+            intensity,
+            cos_inner: f32::cos(f32::to_radians(0.5 * inner_angle)),
+            cos_outer: f32::cos(f32::to_radians(0.5 * outer_angle)),
+        }
+    }
+
+    // `to_light` points towards the sun, `angular_diameter` in degrees (the real sun is ~0.53)
+    pub fn directional(to_light: Vec3, irradiance: Color, angular_diameter: f32) -> AnalyticLight {
+        AnalyticLight::Directional {
+            to_light: to_light.normalized(),
+            irradiance,
+            cos_half_angle: f32::cos(f32::to_radians(0.5 * angular_diameter)),
+        }
+    }
+
+    // Ray leaving the light and the power it carries. Directional lights shoot at the
+    // bounding sphere of the scene given by `center` and `radius`.
+    pub fn sample_emission(
+        &self,
+        center: Vec3,
+        radius: f32,
+        sampler: &mut dyn Sampler,
+    ) -> (Ray, Color) {
+        match *self {
+            AnalyticLight::Point {
+                position,
+                intensity,
+            } => {
+                let dir = Vec3::random_unit_vector(sampler);
+                let power = intensity * (4.0 * std::f32::consts::PI);
+                (Ray::new(position, dir), power)
+            }
+            AnalyticLight::Spot {
+                position,
+                direction,
+                intensity,
+                cos_inner,
+                cos_outer,
+            } => {
+                let (dir, solid_angle) = sample_cone(direction, cos_outer, sampler);
+                let falloff = smoothstep(cos_outer, cos_inner, Vec3::dot(dir, direction));
+                (Ray::new(position, dir), intensity * (falloff * solid_angle))
+            }
+            AnalyticLight::Directional {
+                to_light,
+                irradiance,
+                cos_half_angle,
+            } => {
+                let (dir, _) = sample_cone(to_light, cos_half_angle, sampler);
+                let origin = sample_disk(center + dir * radius, dir, radius, sampler);
+                let area = std::f32::consts::PI * radius * radius;
+                (Ray::new(origin, -dir), irradiance * area)
+            }
+        }
+    }
+
+    pub fn sample(&self, point: Vec3, sampler: &mut dyn Sampler) -> Option<AnalyticSample> {
+        match *self {
+            AnalyticLight::Point {
+                position,
+                intensity,
+            } => {
+                let to_light = position - point;
+                let distance2 = to_light.length2();
+                let distance = distance2.sqrt();
+                Some(AnalyticSample {
+                    dir: to_light / distance,
+                    distance,
+                    weight: intensity * (1.0 / distance2),
+                })
+            }
+            AnalyticLight::Spot {
+                position,
+                direction,
+                intensity,
+                cos_inner,
+                cos_outer,
+            } => {
+                let to_light = position - point;
+                let distance2 = to_light.length2();
+                let distance = distance2.sqrt();
+                let dir = to_light / distance;
+                let falloff = smoothstep(cos_outer, cos_inner, Vec3::dot(-dir, direction));
+                if falloff <= 0.0 {
+                    return None;
+                }
+                Some(AnalyticSample {
+                    dir,
+                    distance,
+                    weight: intensity * (falloff / distance2),
+                })
+            }
+            AnalyticLight::Directional {
+                to_light,
+                irradiance,
+                cos_half_angle,
+            } => {
+                let (dir, _) = sample_cone(to_light, cos_half_angle, sampler);
+                Some(AnalyticSample {
+                    dir,
+                    distance: f32::INFINITY,
+                    weight: irradiance,
+                })
+            }
+        }
+    }
+}
+
+// Uniform direction in the cone around `axis` and the cone's solid angle
+fn sample_cone(axis: Vec3, cos_half_angle: f32, sampler: &mut dyn Sampler) -> (Vec3, f32) {
+    let z = 1.0 + sampler.next_1d() * (cos_half_angle - 1.0);
+    let phi = 2.0 * std::f32::consts::PI * sampler.next_1d();
+    let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - z * z));
+    let local = Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, z);
+    let solid_angle = 2.0 * std::f32::consts::PI * (1.0 - cos_half_angle);
+    (Onb::from_w(axis).local(local), solid_angle)
+}
+
+// Uniform point on the disk of `radius` through `center` facing `normal`
+pub fn sample_disk(center: Vec3, normal: Vec3, radius: f32, sampler: &mut dyn Sampler) -> Vec3 {
+    let frame = Onb::from_w(normal);
+    let offset = Vec3::on_unit_disc(sampler) * radius;
+    center + frame.u * offset.x + frame.v * offset.y
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    if edge1 <= edge0 {
+        return if x >= edge1 { 1.0 } else { 0.0 };
+    }
+    let t = crate::maths::clamp(0.0, 1.0, (x - edge0) / (edge1 - edge0));
+    t * t * (3.0 - 2.0 * t)
+}
+
+pub struct EmitterSample {
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub emission: Color,
+    // Area density including the choice of emitter
+    pub pdf: f32,
+}
+
+// What a light sample was aimed at. The sample only counts if its ray reaches that
+// emitter first, light reached through other emitters belongs to their own samples.
+#[derive(Clone, Copy)]
+pub enum LightTarget {
+    Emitter(usize),
+    Environment,
+}
+
+pub struct LightSample {
+    pub dir: Vec3,
+    // Solid angle density including the choice of light
+    pub pdf: f32,
+    pub target: LightTarget,
+}
+
+pub struct Lights {
+    emitters: Vec<HittablePtr>,
+    emitter_ids: HashMap<usize, usize>,
+    // Built on first use so that adding emitters one by one stays cheap
+    tree: OnceLock<LightTree>,
+    analytic: Vec<AnalyticLight>,
+    environment: Option<EnvironmentPtr>,
+}
+
+impl Lights {
+    pub fn new() -> Lights {
+        Lights {
+            emitters: Vec::new(),
+            emitter_ids: HashMap::new(),
+            tree: OnceLock::new(),
+            analytic: Vec::new(),
+            environment: None,
+        }
+    }
+
+    pub fn from_list(list: &HittableList) -> Lights {
+        let mut lights = Lights::new();
+        for object in list.objects() {
+            if let Some(material) = object.material() {
+                if !material.emitted().is_black() {
+                    lights.add(object.clone());
+                }
+            }
+        }
+        lights
+    }
+
+    pub fn add(&mut self, emitter: HittablePtr) {
+        if let Some(id) = emitter.object_id() {
+            self.emitter_ids.insert(id, self.emitters.len());
+        }
+        self.emitters.push(emitter);
+        self.tree = OnceLock::new();
+    }
+
+    fn tree(&self) -> &LightTree {
+        self.tree.get_or_init(|| LightTree::build(&self.emitters))
+    }
+
+    // Uniformly picks an emitter and a point on its surface
+    pub fn sample_emitter_surface(&self, sampler: &mut dyn Sampler) -> Option<EmitterSample> {
+        if self.emitters.is_empty() {
+            return None;
+        }
+
+        let emitter = &self.emitters[self.random_emitter_index(sampler)];
+        let (point, normal) = emitter.sample_surface(sampler)?;
+        Some(EmitterSample {
+            point,
+            normal,
+            emission: emitter.material()?.emitted(),
+            pdf: 1.0 / (self.emitters.len() as f32 * emitter.area()),
+        })
+    }
+
+    // Area density of sample_emitter_surface picking a point on the object `object_id`
+    pub fn emitter_surface_pdf(&self, object_id: usize) -> f32 {
+        match self.emitter_ids.get(&object_id) {
+            Some(&index) => 1.0 / (self.emitters.len() as f32 * self.emitters[index].area()),
+            None => 0.0,
+        }
+    }
+
+    fn random_emitter_index(&self, sampler: &mut dyn Sampler) -> usize {
+        let count = self.emitters.len();
+        usize::min((sampler.next_1d() * count as f32) as usize, count - 1)
+    }
+
+    pub fn add_analytic(&mut self, light: AnalyticLight) {
+        self.analytic.push(light);
+    }
+
+    pub fn analytic(&self) -> &[AnalyticLight] {
+        &self.analytic
+    }
+
+    pub fn set_environment(&mut self, environment: EnvironmentPtr) {
+        self.environment = Some(environment);
+    }
+
+    pub fn environment(&self) -> Option<&EnvironmentPtr> {
+        self.environment.as_ref()
+    }
+
+    pub fn emitter_count(&self) -> usize {
+        self.emitters.len()
+    }
+
+    // Light groups split the image up by the light it came from: the analytic lights, then
+    // the emitters, then whatever rays see when they miss the scene
+    pub fn group_names(&self) -> Vec<String> {
+        let analytic = (0..self.analytic.len()).map(|index| format!("light{}", index));
+        let emitters = (0..self.emitters.len()).map(|index| format!("emitter{}", index));
+        analytic
+            .chain(emitters)
+            .chain(std::iter::once(String::from("background")))
+            .collect()
+    }
+
+    pub fn analytic_group(&self, index: usize) -> usize {
+        index
+    }
+
+    pub fn emitter_group(&self, object_id: usize) -> Option<usize> {
+        let index = self.emitter_ids.get(&object_id)?;
+        Some(self.analytic.len() + index)
+    }
+
+    pub fn background_group(&self) -> usize {
+        self.analytic.len() + self.emitters.len()
+    }
+
+    pub fn has_environment(&self) -> bool {
+        self.environment.is_some()
+    }
+
+    // True when there is anything for sample_direction to pick
+    pub fn is_sampleable(&self) -> bool {
+        !self.emitters.is_empty() || self.environment.is_some()
+    }
+
+    // Half of the samples go to the environment when there are emitters as well
+    fn environment_probability(&self) -> f32 {
+        match (&self.environment, self.emitters.is_empty()) {
+            (None, _) => 0.0,
+            (Some(_), true) => 1.0,
+            (Some(_), false) => 0.5,
+        }
+    }
+
+    // Direction towards the environment or an emitter picked by the light tree by its
+    // estimated contribution at `origin`
+    pub fn sample_direction(&self, origin: Vec3, sampler: &mut dyn Sampler) -> Option<LightSample> {
+        let env_probability = self.environment_probability();
+        if let Some(environment) = &self.environment {
+            if sampler.next_1d() < env_probability {
+                let dir = environment.random_direction(sampler).normalized();
+                return Some(LightSample {
+                    dir,
+                    pdf: env_probability * environment.pdf_value(dir),
+                    target: LightTarget::Environment,
+                });
+            }
+        }
+
+        let (index, pmf) = self.tree().sample(origin, sampler.next_1d())?;
+        let emitter = &self.emitters[index];
+        let dir = emitter.random_direction(origin, sampler).normalized();
+        Some(LightSample {
+            dir,
+            pdf: (1.0 - env_probability) * pmf * emitter.pdf_value(origin, dir),
+            target: LightTarget::Emitter(emitter.object_id()?),
+        })
+    }
+
+    // Density of sample_direction returning `dir` aimed at the emitter `object_id`
+    pub fn emitter_pdf(&self, origin: Vec3, dir: Vec3, object_id: usize) -> f32 {
+        let index = match self.emitter_ids.get(&object_id) {
+            Some(&index) => index,
+            None => return 0.0,
+        };
+        let pmf = self.tree().pmf(origin, index);
+        if pmf <= 0.0 {
+            return 0.0;
+        }
+        let emitter = &self.emitters[index];
+        (1.0 - self.environment_probability()) * pmf * emitter.pdf_value(origin, dir.normalized())
+    }
+
+    // Density of sample_direction returning `dir` aimed at the environment
+    pub fn environment_pdf(&self, dir: Vec3) -> f32 {
+        match &self.environment {
+            Some(environment) => {
+                self.environment_probability() * environment.pdf_value(dir.normalized())
+            }
+            None => 0.0,
+        }
+    }
+}
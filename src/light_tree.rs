@@ -0,0 +1,370 @@
+use std::f32::consts::PI;
+
+use crate::aabb::Aabb;
+use crate::hittable::HittablePtr;
+use crate::maths::{clamp, Vec3};
+
+const SPLIT_BUCKETS: usize = 12;
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+// Bounding cone of directions, an empty cone has cos_theta = 1 around any axis
+#[derive(Clone, Copy)]
+struct DirectionCone {
+    w: Vec3,
+    cos_theta: f32,
+}
+
+impl DirectionCone {
+    fn union(a: DirectionCone, b: DirectionCone) -> DirectionCone {
+        let theta_a = f32::acos(clamp(-1.0, 1.0, a.cos_theta));
+        let theta_b = f32::acos(clamp(-1.0, 1.0, b.cos_theta));
+        let theta_d = f32::acos(clamp(-1.0, 1.0, Vec3::dot(a.w, b.w)));
+
+        // One cone already contains the other
+        if f32::min(theta_d + theta_b, PI) <= theta_a {
+            return a;
+        }
+        if f32::min(theta_d + theta_a, PI) <= theta_b {
+            return b;
+        }
+
+        let theta_o = 0.5 * (theta_a + theta_d + theta_b);
+        let axis = Vec3::cross(a.w, b.w);
+        if theta_o >= PI || axis.length2() < 1e-12 {
+            return DirectionCone {
+                w: a.w,
+                cos_theta: -1.0,
+            };
+        }
+
+        DirectionCone {
+            w: rotate(a.w, axis.normalized(), theta_o - theta_a),
+            cos_theta: f32::cos(theta_o),
+        }
+    }
+}
+
+// Rodrigues' rotation of `v` by `angle` around the unit vector `axis`
+fn rotate(v: Vec3, axis: Vec3, angle: f32) -> Vec3 {
+    let (sin, cos) = angle.sin_cos();
+    v * cos + Vec3::cross(axis, v) * sin + axis * (Vec3::dot(axis, v) * (1.0 - cos))
+}
+
+// Spatial and directional bounds of a set of emitters (Conty Estevez and Kulla 2018, as
+// in pbrt-v4). Emitters radiate from both sides of their surface, within `cos_theta_e`
+// of any normal inside the `normals` cone.
+#[derive(Clone, Copy)]
+struct LightBounds {
+    bounds: Aabb,
+    phi: f32,
+    normals: DirectionCone,
+    cos_theta_e: f32,
+}
+
+impl LightBounds {
+    fn from_emitter(emitter: &HittablePtr) -> Option<LightBounds> {
+        let bounds = emitter.bounding_box()?;
+        let emission = emitter.material()?.emitted();
+        let (w, cos_theta) = emitter.normal_bounds();
+        Some(LightBounds {
+            bounds,
+            phi: emission.max_component() * emitter.area(),
+            normals: DirectionCone { w, cos_theta },
+            cos_theta_e: 0.0,
+        })
+    }
+
+    fn union(a: LightBounds, b: LightBounds) -> LightBounds {
+        LightBounds {
+            bounds: a.bounds.combine(b.bounds),
+            phi: a.phi + b.phi,
+            normals: DirectionCone::union(a.normals, b.normals),
+            cos_theta_e: f32::min(a.cos_theta_e, b.cos_theta_e),
+        }
+    }
+
+    // Conservative estimate of the light the bounded emitters send towards `point`. It only
+    // depends on the position so that the pmf can be evaluated again from a ray origin.
+    fn importance(&self, point: Vec3) -> f32 {
+        let center = self.bounds.centroid();
+        let radius = 0.5 * self.bounds.diagonal().length();
+        let offset = point - center;
+        let distance2 = offset.length2();
+        let clamped_distance2 = f32::max(distance2, radius * radius);
+
+        // Inside the bounding sphere every direction may reach the point
+        if distance2 <= radius * radius {
+            return self.phi / clamped_distance2;
+        }
+
+        let sin2_theta_b = radius * radius / distance2;
+        let cos_theta_b = safe_sqrt(1.0 - sin2_theta_b);
+        let sin_theta_b = f32::sqrt(sin2_theta_b);
+
+        let cos_theta_w = f32::abs(Vec3::dot(self.normals.w, offset / distance2.sqrt()));
+        let sin_theta_w = safe_sqrt(1.0 - cos_theta_w * cos_theta_w);
+        let cos_theta_o = self.normals.cos_theta;
+        let sin_theta_o = safe_sqrt(1.0 - cos_theta_o * cos_theta_o);
+
+        // Smallest angle between the point and any normal in the cone, less the bounds' spread
+        let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, cos_theta_o);
+        let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, cos_theta_o);
+        let cos_theta_p = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta_p <= self.cos_theta_e {
+            return 0.0;
+        }
+
+        self.phi * cos_theta_p / clamped_distance2
+    }
+
+    // Surface area orientation heuristic of a node made of these bounds
+    fn cost(&self, parent: &Aabb, axis: usize) -> f32 {
+        let theta_o = f32::acos(clamp(-1.0, 1.0, self.normals.cos_theta));
+        let theta_e = f32::acos(clamp(-1.0, 1.0, self.cos_theta_e));
+        let theta_w = f32::min(theta_o + theta_e, PI);
+        let sin_theta_o = safe_sqrt(1.0 - self.normals.cos_theta * self.normals.cos_theta);
+        let m_omega = 2.0 * PI * (1.0 - self.normals.cos_theta)
+            + PI / 2.0
+                * (2.0 * theta_w * sin_theta_o
+                    - f32::cos(theta_o - 2.0 * theta_w)
+                    - 2.0 * theta_o * sin_theta_o
+                    + self.normals.cos_theta);
+
+        // Penalizes thin slabs that the surface area alone would favour
+        let diagonal = parent.diagonal();
+        let max_extent = f32::max(diagonal.x, f32::max(diagonal.y, diagonal.z));
+        let kr = max_extent / f32::max(diagonal[axis], 1e-8);
+        self.phi * m_omega * kr * self.bounds.surface_area()
+    }
+}
+
+fn safe_sqrt(x: f32) -> f32 {
+    f32::sqrt(f32::max(x, 0.0))
+}
+
+// cos(max(0, a - b)) and sin(max(0, a - b)) from the sines and cosines of a and b
+fn cos_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if cos_a > cos_b {
+        return 1.0;
+    }
+    cos_a * cos_b + sin_a * sin_b
+}
+
+fn sin_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if cos_a > cos_b {
+        return 0.0;
+    }
+    sin_a * cos_b - cos_a * sin_b
+}
+
+enum NodeKind {
+    Leaf(usize),
+    Interior(usize, usize),
+}
+
+struct LightNode {
+    bounds: LightBounds,
+    kind: NodeKind,
+}
+
+// Bounding volume hierarchy over the emitters, traversed stochastically by the
+// importance of each child so that lights are picked roughly by their contribution
+pub struct LightTree {
+    nodes: Vec<LightNode>,
+    parents: Vec<Option<usize>>,
+    // Leaf node of every emitter, None for emitters that cannot be bounded
+    leaves: Vec<Option<usize>>,
+}
+
+impl LightTree {
+    pub fn build(emitters: &[HittablePtr]) -> LightTree {
+        let mut items: Vec<(usize, LightBounds)> = emitters
+            .iter()
+            .enumerate()
+            .filter_map(|(index, emitter)| Some((index, LightBounds::from_emitter(emitter)?)))
+            .filter(|(_, bounds)| bounds.phi > 0.0)
+            .collect();
+
+        let mut tree = LightTree {
+            nodes: Vec::new(),
+            parents: Vec::new(),
+            leaves: vec![None; emitters.len()],
+        };
+        if !items.is_empty() {
+            tree.build_node(&mut items, None);
+        }
+        tree
+    }
+
+    fn build_node(&mut self, items: &mut [(usize, LightBounds)], parent: Option<usize>) -> usize {
+        let index = self.nodes.len();
+        self.parents.push(parent);
+
+        if let [(emitter, bounds)] = *items {
+            self.nodes.push(LightNode {
+                bounds,
+                kind: NodeKind::Leaf(emitter),
+            });
+            self.leaves[emitter] = Some(index);
+            return index;
+        }
+
+        let bounds = items
+            .iter()
+            .map(|(_, bounds)| *bounds)
+            .reduce(LightBounds::union)
+            .unwrap();
+        self.nodes.push(LightNode {
+            bounds,
+            kind: NodeKind::Interior(0, 0),
+        });
+
+        let mid = split(items, &bounds.bounds);
+        let (below, above) = items.split_at_mut(mid);
+        let left = self.build_node(below, Some(index));
+        let right = self.build_node(above, Some(index));
+        self.nodes[index].kind = NodeKind::Interior(left, right);
+        index
+    }
+
+    // Picks an emitter for shading `point` with the uniform number `u`, returning its
+    // index and probability
+    pub fn sample(&self, point: Vec3, u: f32) -> Option<(usize, f32)> {
+        let mut node = self.nodes.first()?;
+        if node.bounds.importance(point) <= 0.0 {
+            return None;
+        }
+
+        let mut u = u;
+        let mut pmf = 1.0;
+        loop {
+            match node.kind {
+                NodeKind::Leaf(emitter) => return Some((emitter, pmf)),
+                NodeKind::Interior(left, right) => {
+                    let left_importance = self.nodes[left].bounds.importance(point);
+                    let right_importance = self.nodes[right].bounds.importance(point);
+                    let total = left_importance + right_importance;
+                    if total <= 0.0 {
+                        return None;
+                    }
+
+                    // Rescaling u to the chosen side leaves it uniform for the next level
+                    let p_left = left_importance / total;
+                    if u < p_left {
+                        u = f32::min(u / p_left, ONE_MINUS_EPSILON);
+                        pmf *= p_left;
+                        node = &self.nodes[left];
+                    } else {
+                        u = f32::min((u - p_left) / (1.0 - p_left), ONE_MINUS_EPSILON);
+                        pmf *= 1.0 - p_left;
+                        node = &self.nodes[right];
+                    }
+                }
+            }
+        }
+    }
+
+    // Probability of sample picking `emitter` at `point`
+    pub fn pmf(&self, point: Vec3, emitter: usize) -> f32 {
+        let mut node = match self.leaves.get(emitter) {
+            Some(Some(leaf)) => *leaf,
+            _ => return 0.0,
+        };
+        if self.nodes[0].bounds.importance(point) <= 0.0 {
+            return 0.0;
+        }
+
+        let mut pmf = 1.0;
+        while let Some(parent) = self.parents[node] {
+            if let NodeKind::Interior(left, right) = self.nodes[parent].kind {
+                let left_importance = self.nodes[left].bounds.importance(point);
+                let right_importance = self.nodes[right].bounds.importance(point);
+                let total = left_importance + right_importance;
+                if total <= 0.0 {
+                    return 0.0;
+                }
+                let importance = if node == left {
+                    left_importance
+                } else {
+                    right_importance
+                };
+                pmf *= importance / total;
+            }
+            node = parent;
+        }
+        pmf
+    }
+}
+
+// Orders `items` along the cheapest bucketed split and returns the size of the first half
+fn split(items: &mut [(usize, LightBounds)], bounds: &Aabb) -> usize {
+    let centroids = items
+        .iter()
+        .map(|(_, light)| {
+            let centroid = light.bounds.centroid();
+            Aabb::new(centroid, centroid)
+        })
+        .reduce(Aabb::combine)
+        .unwrap();
+
+    let mut best: Option<(f32, usize, usize)> = None;
+    for axis in 0..3 {
+        let min = centroids.min[axis];
+        let extent = centroids.max[axis] - min;
+        if extent <= 0.0 {
+            continue;
+        }
+
+        let mut buckets: [Option<LightBounds>; SPLIT_BUCKETS] = [None; SPLIT_BUCKETS];
+        for (_, light) in items.iter() {
+            let bucket = &mut buckets[bucket_of(light, axis, min, extent)];
+            *bucket = Some(match *bucket {
+                Some(existing) => LightBounds::union(existing, *light),
+                None => *light,
+            });
+        }
+
+        for split in 0..SPLIT_BUCKETS - 1 {
+            let union = |buckets: &[Option<LightBounds>]| {
+                buckets.iter().flatten().copied().reduce(LightBounds::union)
+            };
+            let cost = [union(&buckets[..=split]), union(&buckets[split + 1..])]
+                .iter()
+                .flatten()
+                .map(|side| side.cost(bounds, axis))
+                .sum::<f32>();
+            if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                best = Some((cost, axis, split));
+            }
+        }
+    }
+
+    // Coincident centroids have nothing to split by
+    let (axis, split) = match best {
+        Some((_, axis, split)) => (axis, split),
+        None => return items.len() / 2,
+    };
+
+    let min = centroids.min[axis];
+    let extent = centroids.max[axis] - min;
+    items.sort_by(|(_, a), (_, b)| {
+        let a = a.bounds.centroid()[axis];
+        let b = b.bounds.centroid()[axis];
+        a.total_cmp(&b)
+    });
+    let below = items
+        .iter()
+        .filter(|(_, light)| bucket_of(light, axis, min, extent) <= split)
+        .count();
+
+    if below == 0 || below == items.len() {
+        items.len() / 2
+    } else {
+        below
+    }
+}
+
+fn bucket_of(light: &LightBounds, axis: usize, min: f32, extent: f32) -> usize {
+    let offset = (light.bounds.centroid()[axis] - min) / extent;
+    usize::min((offset * SPLIT_BUCKETS as f32) as usize, SPLIT_BUCKETS - 1)
+}
@@ -1,26 +1,58 @@
 #![allow(dead_code)]
 use core::f32;
 use std::ops::Deref;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 use crate::bvh::Bvh;
 use crate::color::Color;
+use crate::environment::EnvironmentMap;
+use crate::checkpoint::Checkpoint;
+use crate::film::{Film, PixelStats, SplatBuffer};
 use crate::helpers::*;
 use crate::hittable::HittableList;
+use crate::integrator::IntegratorPtr;
+use crate::light::{AnalyticLight, Lights};
+use crate::sampler::{Sampler, SamplerPtr};
+use crate::scene::{Background, Scene};
+use crate::sky::PreethamSky;
 use crate::maths::Vec3;
-
-type TsImage = Arc<Mutex<Vec<Color>>>;
+use crate::output::{write_image, write_layers};
 
 mod aabb;
+mod bdpt;
+mod blue_noise;
 mod bvh;
 mod camera;
+mod checkpoint;
 mod color;
+mod distributed;
+mod distribution;
+mod environment;
+mod film;
+mod guiding;
 mod helpers;
 mod hittable;
+mod integrator;
+mod light;
+mod light_tree;
 mod material;
 mod maths;
+mod mlt;
+mod output;
+mod sampler;
+mod scene;
+mod sky;
+mod spectral;
+mod spectrum;
+mod sppm;
+mod tiles;
+mod tonemap;
 
-fn make_world() -> Arc<Bvh> {
+fn make_world(
+    background: Background,
+    glass_ior: material::Ior,
+    analytic_lights: &[AnalyticLight],
+) -> Arc<Scene> {
     let mut world = HittableList::new();
     //let sphere = hittable::Sphere::new(0.0, 0.0, -1.0, 0.5);
     let lambert_red = material::Lambertian::create(Color {
@@ -54,13 +86,13 @@ fn make_world() -> Arc<Bvh> {
         },
         0.11,
     );
-    let glass = material::Dieletric::create(
+    let glass = material::Dieletric::with_ior(
         Color {
             r: 1.0,
             g: 1.0,
             b: 1.0,
         },
-        1.5,
+        glass_ior,
     );
     let ground_mat = material::Lambertian::create(Color {
         r: 0.2,
@@ -102,7 +134,26 @@ fn make_world() -> Arc<Bvh> {
         0.0, -100.5, -3.0, 100.0, ground_mat,
     ));
 
-    std::sync::Arc::new(Bvh::new(world))
+    let mut lights = Lights::from_list(&world);
+    for &light in analytic_lights {
+        lights.add_analytic(light);
+    }
+    if let Background::Environment(map) = &background {
+        lights.set_environment(map.clone());
+    }
+    std::sync::Arc::new(Scene {
+        world: Bvh::new(world),
+        lights,
+        background,
+    })
+}
+
+// Comma separated numbers, as in -point-light=x,y,z,r,g,b
+fn parse_floats(value: &str) -> Vec<f32> {
+    value
+        .split(',')
+        .map(|number| number.trim().parse::<f32>().expect("invalid number"))
+        .collect()
 }
 
 fn collect_normals<T: hittable::Hittable>(
@@ -139,6 +190,277 @@ fn collect_albedo<T: hittable::Hittable>(
     normals
 }
 
+// Distance to the first hit along the camera ray, its position and the id of the object hit
+// plus one, with infinity, black and zero for rays that miss
+fn collect_first_hits<T: hittable::Hittable>(
+    world: &T,
+    camera: camera::Camera,
+    width: i32,
+    height: i32,
+) -> (Vec<Color>, Vec<Color>, Vec<Color>) {
+    let mut depth = Vec::<Color>::new();
+    let mut position = Vec::<Color>::new();
+    let mut object_id = Vec::<Color>::new();
+    for y in 0..height {
+        for x in 0..width {
+            let u = x as f32 / (width as f32 - 1.0);
+            let v = y as f32 / (height as f32 - 1.0);
+            let ray = camera.straight_ray(u, v);
+            match world.hit(0.01, f32::INFINITY, &ray) {
+                Some(hit) => {
+                    let distance = hit.t * ray.dir.length();
+                    depth.push(Color::new(distance, distance, distance));
+                    position.push(Color::from_vec3(hit.point));
+                    let id = (hit.object_id + 1) as f32;
+                    object_id.push(Color::new(id, id, id));
+                }
+                None => {
+                    depth.push(Color::new(f32::INFINITY, f32::INFINITY, f32::INFINITY));
+                    position.push(color::BLACK);
+                    object_id.push(color::BLACK);
+                }
+            }
+        }
+    }
+    (depth, position, object_id)
+}
+
+#[derive(Clone)]
+struct RenderSettings {
+    width: i32,
+    height: i32,
+    num_threads: i32,
+    // Pixels stop sampling once their relative error falls below this, checked every
+    // min_samples samples. The sampler's samples_per_pixel is the maximum.
+    noise_threshold: Option<f32>,
+    min_samples: u32,
+    // Progressive renders go over the whole image pass_samples samples at a time and save
+    // a snapshot every snapshot_passes passes or snapshot_seconds seconds
+    pass_samples: u32,
+    snapshot_passes: Option<u32>,
+    snapshot_seconds: Option<f32>,
+    // Keeps adding passes until this many seconds have been spent rendering, instead of
+    // stopping at the sampler's samples_per_pixel
+    time_limit: Option<f32>,
+    // Saves the render state here every checkpoint_seconds seconds and when interrupted
+    checkpoint_path: Option<String>,
+    checkpoint_seconds: f32,
+}
+
+impl RenderSettings {
+    fn progressive(&self) -> bool {
+        self.snapshot_passes.is_some()
+            || self.snapshot_seconds.is_some()
+            || self.time_limit.is_some()
+            || self.checkpoint_path.is_some()
+    }
+}
+
+// Renders every pixel in passes, continuing from state, and calls snapshot with the image so
+// far between passes of a progressive render. Returns the film and the samples per pixel
+// it took.
+fn render_tiles(
+    scene: &Scene,
+    integrator: IntegratorPtr,
+    camera: camera::Camera,
+    sampler: SamplerPtr,
+    settings: &RenderSettings,
+    mut state: Checkpoint,
+    mut snapshot: impl FnMut(&[Color]),
+) -> (Film, u32) {
+    let samples_per_pixel = match settings.time_limit {
+        Some(_) => u32::MAX,
+        None => sampler.samples_per_pixel(),
+    };
+    let pass_samples = if settings.progressive() {
+        settings.pass_samples.max(1)
+    } else {
+        samples_per_pixel
+    };
+
+    let render_start = std::time::Instant::now();
+    let mut last_snapshot = render_start;
+    let mut last_checkpoint = render_start;
+    while state.samples_per_pixel < samples_per_pixel {
+        let begin = state.samples_per_pixel;
+        let end = u32::saturating_add(begin, pass_samples).min(samples_per_pixel);
+        let pass_start = std::time::Instant::now();
+        let samples_before = state.film.samples();
+        render_pass(scene, &integrator, camera, &sampler, &mut state.film, begin..end, settings);
+        state.samples_per_pixel = end;
+        state.passes += 1;
+
+        // Stops before a pass that would not finish within the time limit, or once adaptive
+        // sampling has nothing left to do
+        let out_of_time = settings.time_limit.is_some_and(|limit| {
+            let finished = render_start.elapsed() + pass_start.elapsed();
+            state.film.samples() == samples_before || finished.as_secs_f32() > limit
+        });
+        let finished = out_of_time || end == samples_per_pixel;
+
+        let interrupted = checkpoint::interrupted();
+        if let Some(path) = &settings.checkpoint_path {
+            let due = last_checkpoint.elapsed().as_secs_f32() >= settings.checkpoint_seconds;
+            if due || finished || interrupted {
+                state.save(path).expect("failed to save checkpoint");
+                println!("Saved checkpoint {} at {} samples per pixel", path, end);
+                last_checkpoint = std::time::Instant::now();
+            }
+        }
+        if interrupted {
+            println!("Interrupted after {} samples per pixel", end);
+        }
+        if finished || interrupted {
+            break;
+        }
+
+        let due = settings
+            .snapshot_passes
+            .is_some_and(|passes| state.passes.is_multiple_of(passes.max(1)))
+            || settings
+                .snapshot_seconds
+                .is_some_and(|seconds| last_snapshot.elapsed().as_secs_f32() >= seconds);
+        if due {
+            println!("Pass {}: {} samples per pixel", state.passes, end);
+            snapshot(&state.film.image());
+            last_snapshot = std::time::Instant::now();
+        }
+    }
+
+    if settings.noise_threshold.is_some() {
+        println!(
+            "Adaptive sampling used {:.1} samples per pixel on average",
+            state.film.samples() as f32 / (state.film.width() * state.film.height()) as f32
+        );
+    }
+    (state.film, state.samples_per_pixel)
+}
+
+// Camera samples whose splats a pass keeps in memory at once, each making a few of them
+const SPLAT_SAMPLES: usize = 1 << 20;
+
+// Adds samples of every pixel to the film. Worker threads pull small tiles from a shared
+// queue, so no thread idles while others are still busy with an expensive part of the image.
+fn render_pass(
+    scene: &Scene,
+    integrator: &IntegratorPtr,
+    camera: camera::Camera,
+    sampler: &SamplerPtr,
+    film: &mut Film,
+    samples: std::ops::Range<u32>,
+    settings: &RenderSettings,
+) {
+    let RenderSettings {
+        width,
+        height,
+        num_threads,
+        ..
+    } = *settings;
+    let converged = |stats: &PixelStats| match settings.noise_threshold {
+        Some(threshold) => {
+            stats.count().is_multiple_of(settings.min_samples.max(1))
+                && stats.relative_error() < threshold
+        }
+        None => false,
+    };
+
+    // Light groups are only recorded when the film has any, with a buffer per sample
+    let groups = film.light_groups().len();
+    let render_tile = |tile: tiles::Tile,
+                       samples: std::ops::Range<u32>,
+                       pixels: &mut [PixelStats],
+                       light_sums: &mut [Color],
+                       sampler: &mut dyn Sampler,
+                       splats: &mut SplatBuffer| {
+        let mut samples_taken = 0u64;
+        let mut contributions = vec![color::BLACK; groups];
+        for y in tile.y0..tile.y1 {
+            for x in tile.x0..tile.x1 {
+                let pixel = ((y - tile.y0) * tile.width() + x - tile.x0) as usize;
+                let stats = &mut pixels[pixel];
+                let sums = &mut light_sums[pixel * groups..(pixel + 1) * groups];
+                for sample_index in samples.clone() {
+                    if converged(stats) {
+                        break;
+                    }
+                    sampler.start_pixel_sample(x, y, sample_index);
+                    let ru = sampler.next_1d();
+                    let rv = sampler.next_1d();
+                    let v = (y as f32 + rv) / (height as f32 - 1.0);
+                    let u = (x as f32 + ru) / (width as f32 - 1.0);
+                    let ray = camera.get_ray(u, v, sampler);
+                    if groups == 0 {
+                        stats.add(integrator.radiance(ray, scene, splats, sampler));
+                    } else {
+                        contributions.fill(color::BLACK);
+                        let groups = &mut contributions;
+                        let radiance =
+                            integrator.radiance_by_light(ray, scene, splats, groups, sampler);
+                        stats.add(radiance);
+                        for (sum, &contribution) in sums.iter_mut().zip(contributions.iter()) {
+                            *sum += contribution;
+                        }
+                    }
+                    samples_taken += 1;
+                }
+            }
+        }
+        samples_taken
+    };
+
+    // Every tile keeps its own splats, added to the film in tile order once all workers are
+    // done, so the image doesn't depend on which thread rendered which tile. Integrators that
+    // splat go through the pass in chunks of samples to bound the splats held at once.
+    let chunk = if integrator.splats() {
+        u32::max((SPLAT_SAMPLES / (width * height) as usize) as u32, 1)
+    } else {
+        u32::max(samples.len() as u32, 1)
+    };
+    for begin in samples.clone().step_by(chunk as usize) {
+        let samples = begin..u32::min(begin + chunk, samples.end);
+        let queue = tiles::TileQueue::new(film.tiles().to_vec());
+        let workers: Vec<_> = std::thread::scope(|threads| {
+            let handles: Vec<_> = (0..usize::max(num_threads as usize, 1))
+                .map(|_| {
+                    let mut sampler = sampler.clone_sampler();
+                    let (queue, film, render_tile) = (&queue, &*film, &render_tile);
+                    let samples = samples.clone();
+                    threads.spawn(move || {
+                        let mut tile_splats = Vec::new();
+                        let mut samples_taken = 0;
+                        while let Some((index, tile)) = queue.next() {
+                            let mut pixels = film.tile_pixels(index);
+                            let mut sums = film.tile_light_sums(index);
+                            let mut splats = SplatBuffer::new(width, height);
+                            samples_taken += render_tile(
+                                tile,
+                                samples.clone(),
+                                &mut pixels,
+                                &mut sums,
+                                sampler.as_mut(),
+                                &mut splats,
+                            );
+                            tile_splats.push((index, splats));
+                        }
+                        (tile_splats, samples_taken)
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+
+        let mut tile_splats = Vec::new();
+        for (splats, samples_taken) in workers {
+            tile_splats.extend(splats);
+            film.add_samples(samples_taken);
+        }
+        tile_splats.sort_unstable_by_key(|&(index, _)| index);
+        for (_, splats) in &tile_splats {
+            film.add_splats(splats);
+        }
+    }
+}
+
 fn main() {
     // Image
     const bg : color::Color = color::Color{r:0.0001, g:0.0002, b:0.002};
@@ -146,24 +468,194 @@ fn main() {
     let mut samples_per_pixel = 400;
     let mut width = 2000;
     let mut depth = 100;
+    let mut integrator_name = String::from("path");
+    let mut sampler_name = String::from("independent");
+    let mut seed = 0;
+    let mut noise_threshold = None;
+    let mut min_samples = 16;
+    let mut pass_samples = 1;
+    let mut snapshot_passes = None;
+    let mut snapshot_seconds = None;
+    let mut time_limit = None;
+    let mut checkpoint_path = None;
+    let mut checkpoint_seconds = 600.0;
+    let mut resume_path = None;
+    let mut coordinator_address = None;
+    let mut worker_address = None;
+    let mut merge_paths = None;
+    let mut output_flags = Vec::new();
+    let mut exr_precision = String::from("float");
+    let mut exr_compression = String::from("zip");
+    let mut tone_mapping = tonemap::ToneMapping::new();
+    let mut env_path = None;
+    let mut env_rotation = 0.0;
+    let mut env_intensity = 1.0;
+    let mut sky_turbidity = None;
+    let mut sun_elevation = 45.0;
+    let mut sun_azimuth = 0.0;
+    let mut sky_intensity = 1.0;
+    let mut analytic_lights = Vec::new();
+    let mut photons = None;
+    let mut photon_radius = 0.05;
+    let mut photon_alpha = 2.0 / 3.0;
+    let mut glass_ior = material::Ior::Constant(1.5);
+    let mut bootstrap_samples = 100000;
+    let mut chains = 1000;
+    let mut large_step_probability = 0.3;
+    let mut mutation_sigma = 0.01;
 
     for arg in std::env::args() {
-        let mut args = arg.split('=');
+        let mut args = arg.splitn(2, '=');
         let command = args.next().expect("invalid args");
 
         if let Some(value) = args.next() {
-            let value = value.parse::<i32>().expect("invalid number");
+            let number = || value.parse::<i32>().expect("invalid number");
+            let float = || value.parse::<f32>().expect("invalid number");
             match &command[..] {
-                "-t" => num_threads = value,
-                "-s" => samples_per_pixel = value,
-                "-w" => width = value,
-                "-d" => depth = value,
+                "-t" => num_threads = number(),
+                "-s" => samples_per_pixel = number(),
+                "-w" => width = number(),
+                "-d" => depth = number(),
+                "-integrator" => integrator_name = value.to_string(),
+                "-sampler" => sampler_name = value.to_string(),
+                "-seed" => seed = value.parse::<u64>().expect("invalid seed"),
+                "-noise-threshold" => noise_threshold = Some(float()),
+                "-min-samples" => min_samples = number() as u32,
+                "-pass-samples" => pass_samples = number() as u32,
+                "-snapshot-passes" => snapshot_passes = Some(number() as u32),
+                "-snapshot-seconds" => snapshot_seconds = Some(float()),
+                "-time-limit" | "--time-limit" => time_limit = Some(float()),
+                "-checkpoint" => checkpoint_path = Some(value.to_string()),
+                "-checkpoint-seconds" => checkpoint_seconds = float(),
+                "-resume" => resume_path = Some(value.to_string()),
+                "-coordinator" => coordinator_address = Some(value.to_string()),
+                "-worker" => worker_address = Some(value.to_string()),
+                "-merge" => merge_paths = Some(value.to_string()),
+                // Unknown output options are rejected when the outputs are built
+                option if option == "-o" || option.starts_with("-o-") => {
+                    output_flags.push((option.to_string(), value.to_string()))
+                }
+                "-exr-precision" => exr_precision = value.to_string(),
+                "-exr-compression" => exr_compression = value.to_string(),
+                "-tonemap" => {
+                    tone_mapping.curve =
+                        tonemap::Curve::from_name(value).expect("unknown tone mapping curve")
+                }
+                "-exposure" => tone_mapping.exposure = float(),
+                "-white-balance" => tone_mapping.set_white_balance(float()),
+                "-env" => env_path = Some(value.to_string()),
+                "-env-rotation" => env_rotation = float(),
+                "-env-intensity" => env_intensity = float(),
+                "-sky" => sky_turbidity = Some(float()),
+                "-sun-elevation" => sun_elevation = float(),
+                "-sun-azimuth" => sun_azimuth = float(),
+                "-sky-intensity" => sky_intensity = float(),
+                "-point-light" => match parse_floats(value)[..] {
+                    [x, y, z, r, g, b] => analytic_lights.push(AnalyticLight::point(
+                        Vec3::new(x, y, z),
+                        Color::new(r, g, b),
+                    )),
+                    _ => panic!("-point-light takes x,y,z,r,g,b"),
+                },
+                "-spot-light" => match parse_floats(value)[..] {
+                    [x, y, z, tx, ty, tz, r, g, b, inner, outer] => {
+                        analytic_lights.push(AnalyticLight::spot(
+                            Vec3::new(x, y, z),
+                            Vec3::new(tx, ty, tz),
+                            Color::new(r, g, b),
+                            inner,
+                            outer,
+                        ))
+                    }
+                    _ => panic!("-spot-light takes x,y,z,target x,y,z,r,g,b,inner,outer"),
+                },
+                // The angular diameter defaults to that of the real sun
+                "-sun-light" => match parse_floats(value)[..] {
+                    [x, y, z, r, g, b, ref diameter @ ..] if diameter.len() <= 1 => {
+                        analytic_lights.push(AnalyticLight::directional(
+                            Vec3::new(x, y, z),
+                            Color::new(r, g, b),
+                            diameter.first().copied().unwrap_or(0.53),
+                        ))
+                    }
+                    _ => panic!("-sun-light takes x,y,z,r,g,b[,angular diameter]"),
+                },
+                "-glass" => {
+                    glass_ior = match value {
+                        "bk7" => material::Ior::bk7(),
+                        "sf11" => material::Ior::sf11(),
+                        _ => material::Ior::Constant(float()),
+                    }
+                }
+                "-photons" => photons = Some(number() as usize),
+                "-radius" => photon_radius = float(),
+                "-alpha" => photon_alpha = float(),
+                "-bootstrap" => bootstrap_samples = number() as usize,
+                "-chains" => chains = number() as usize,
+                "-large-step" => large_step_probability = float(),
+                "-sigma" => mutation_sigma = float(),
                 _ => {}
             }
         }
     }
 
-    let world = make_world();
+    // Integrators that render the whole image at once have no passes to stop after, continue
+    // from or hand out to other machines
+    let whole_image = matches!(&integrator_name[..], "sppm" | "guided" | "mlt");
+    if whole_image && (checkpoint_path.is_some() || resume_path.is_some()) {
+        panic!("the {} integrator can't checkpoint or resume a render", integrator_name);
+    }
+    if whole_image && (coordinator_address.is_some() || worker_address.is_some()) {
+        panic!("the {} integrator can't render on several machines", integrator_name);
+    }
+    if whole_image && time_limit.is_some() {
+        panic!("the {} integrator can't stop at a time limit", integrator_name);
+    }
+
+    // Every output can override the tone mapping given by the flags
+    let output_settings =
+        output::OutputSettings::new(&exr_precision, &exr_compression, tone_mapping)
+            .expect("unknown EXR precision or compression");
+    if output_flags.is_empty() {
+        output_flags.push((String::from("-o"), String::from("beauty.png")));
+    }
+    let outputs = output::parse_outputs(&output_flags, output_settings).expect("invalid output");
+
+    // Combines checkpoints of separate renders instead of rendering
+    if let Some(paths) = &merge_paths {
+        let paths: Vec<&str> = paths.split(',').collect();
+        let merged = checkpoint::merge(&paths).expect("failed to merge renders");
+        let (width, height) = (merged.film.width(), merged.film.height());
+        let mut layers = merged.film.aovs().to_vec();
+        layers.extend(merged.film.light_images());
+        let image = merged.film.image();
+        for output in &outputs {
+            write_layers(&output.path, &image, &layers, width, height, &output.settings);
+        }
+        if let Some(path) = &checkpoint_path {
+            merged.save(path).expect("failed to save checkpoint");
+        }
+        println!(
+            "Merged {} renders with {} samples per pixel",
+            paths.len(),
+            merged.samples_per_pixel
+        );
+        return;
+    }
+
+    let background = match (&env_path, sky_turbidity) {
+        (Some(path), _) => {
+            let map = EnvironmentMap::load(path, env_rotation, env_intensity)
+                .expect("failed to load environment map");
+            Background::Environment(Arc::new(map))
+        }
+        (None, Some(turbidity)) => {
+            let sky = PreethamSky::new(sun_elevation, sun_azimuth, turbidity, sky_intensity);
+            Background::Environment(Arc::new(sky))
+        }
+        (None, None) => Background::Constant(bg),
+    };
+    let scene = make_world(background, glass_ior, &analytic_lights);
 
     let camera_pos = Vec3::new(5.0, 2.5, 3.0);
     let camera_focus = Vec3::new(1., -0.3, -1.0);
@@ -181,74 +673,190 @@ fn main() {
     let height = (width as f32 / camera.aspect()) as i32;
     // World
 
-    let image = Arc::new(Mutex::new(Vec::<Color>::new()));
-    image
-        .lock()
-        .unwrap()
-        .resize((width * height) as usize, color::BLACK);
-
-    let closure_image = image.clone();
-    let process_image = move |begin, end, world: Arc<Bvh>| {
-        let mut thread_result = Vec::<Color>::new();
-        let scale = 1.0 / samples_per_pixel as f32;
-        for y in begin..end {
-            for x in 0..width {
-                let mut accum_color = color::BLACK;
-                for _ in 0..samples_per_pixel {
-                    let ru = random_float(0.0..1.0);
-                    let rv = random_float(0.0..1.0);
-                    let v = (y as f32 + rv) / (height as f32 - 1.0);
-                    let u = (x as f32 + ru) / (width as f32 - 1.0);
-                    accum_color += ray_color(camera.get_ray(u, v), world.deref(), bg, depth);
-                }
-                thread_result.push(accum_color * scale);
-            }
-        }
+    // Layers written along with the beauty, the per-light ones are added after rendering
+    let normal_data = collect_normals(&scene.world, camera, width, height);
+    let albedo_data = collect_albedo(&scene.world, camera, width, height);
+    let (depth_data, position_data, object_id_data) =
+        collect_first_hits(&scene.world, camera, width, height);
+    let mut layers = vec![
+        (String::from("albedo"), albedo_data),
+        (String::from("normal"), normal_data),
+        (String::from("depth"), depth_data),
+        (String::from("position"), position_data),
+        (String::from("object_id"), object_id_data),
+    ];
 
-        let mut thread_local_canvas = closure_image.lock().unwrap();
-        let offset = begin * width;
-        for (index, color) in thread_result.iter().enumerate() {
-            thread_local_canvas[index + offset as usize] += *color;
+    let time_before_loop = std::time::Instant::now();
+    let (image, light_layers, samples_taken) = match &integrator_name[..] {
+        "sppm" => {
+            let settings = sppm::SppmSettings {
+                iterations: samples_per_pixel,
+                photons: photons.unwrap_or((width * height) as usize),
+                initial_radius: photon_radius,
+                alpha: photon_alpha,
+                max_depth: depth,
+                num_threads,
+                seed,
+            };
+            (
+                sppm::render(scene.deref(), camera, width, height, &settings),
+                Vec::new(),
+                samples_per_pixel as u32,
+            )
         }
-    };
+        "guided" => {
+            let settings = guiding::GuidingSettings {
+                samples_per_pixel,
+                max_depth: depth,
+                num_threads,
+                seed,
+            };
+            (
+                guiding::render(scene.deref(), camera, width, height, &settings),
+                Vec::new(),
+                samples_per_pixel as u32,
+            )
+        }
+        "mlt" => {
+            let settings = mlt::MltSettings {
+                mutations_per_pixel: samples_per_pixel,
+                bootstrap_samples,
+                chains,
+                large_step_probability,
+                sigma: mutation_sigma,
+                max_depth: depth,
+                num_threads,
+                seed,
+            };
+            (
+                mlt::render(scene.deref(), camera, width, height, &settings),
+                Vec::new(),
+                samples_per_pixel as u32,
+            )
+        }
+        _ => {
+            let integrator = integrator::create(&integrator_name, depth, camera, width, height)
+                .expect("unknown integrator");
+            let sampler =
+                sampler::create(&sampler_name, samples_per_pixel, seed).expect("unknown sampler");
 
-    let mut threads = Vec::new();
-    let time_before_loop = std::time::Instant::now();
-    let process_image = std::sync::Arc::new(process_image);
+            // Every setting that changes the image has to match to resume from a checkpoint or
+            // to render on several machines
+            let ignored = [
+                "-t=",
+                "-pass-samples=",
+                "-snapshot-",
+                "-time-limit=",
+                "--time-limit=",
+                "-checkpoint",
+                "-resume=",
+                "-coordinator=",
+                "-worker=",
+                "-o=",
+                "-o-",
+                "-exr-",
+                "-tonemap=",
+                "-exposure=",
+                "-white-balance=",
+            ];
+            let key = std::env::args()
+                .skip(1)
+                .filter(|arg| !ignored.iter().any(|prefix| arg.starts_with(prefix)))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let settings = RenderSettings {
+                width,
+                height,
+                num_threads,
+                noise_threshold,
+                min_samples,
+                pass_samples,
+                snapshot_passes,
+                snapshot_seconds,
+                time_limit,
+                checkpoint_path: checkpoint_path.or(resume_path.clone()),
+                checkpoint_seconds,
+            };
 
-    let subrange_step = height / num_threads;
-    let remainder = height % num_threads;
-    assert!(
-        subrange_step > 0,
-        "dont use more threads than image height :("
-    );
+            // Films of integrators that can tell the lights apart also record each light group
+            let separates_lights = integrator.separates_lights();
+            let new_film = || {
+                let tiles = tiles::spiral(width, height, tiles::TILE_SIZE);
+                let mut film = Film::new(width, height, tiles);
+                if separates_lights {
+                    film.set_light_groups(scene.lights.group_names());
+                }
+                film
+            };
 
-    for thread_num in 0..num_threads {
-        let range_start = thread_num * subrange_step;
-        let mut range_end = (thread_num + 1) * subrange_step;
-        if thread_num == num_threads - 1 {
-            range_end += remainder;
-        }
-        let w = world.clone();
-        let p1 = process_image.clone();
-        let t1 = std::thread::spawn(move || p1.deref()(range_start, range_end, w));
-        threads.push(t1);
-    }
+            if let Some(address) = &coordinator_address {
+                let samples_per_pixel = sampler.samples_per_pixel();
+                let film = distributed::coordinate(
+                    address,
+                    &key,
+                    new_film(),
+                    samples_per_pixel,
+                    pass_samples,
+                )
+                .expect("failed to coordinate workers");
+                (film.image(), film.light_images(), samples_per_pixel)
+            } else if let Some(address) = &worker_address {
+                distributed::work(address, &key, new_film(), |film, samples| {
+                    let scene = scene.deref();
+                    render_pass(scene, &integrator, camera, &sampler, film, samples, &settings)
+                })
+                .expect("lost the coordinator");
+                println!("Done!");
+                return;
+            } else {
+                let mut state = match &resume_path {
+                    Some(path) => {
+                        let state = Checkpoint::load(path).expect("failed to load checkpoint");
+                        assert!(
+                            state.key == key,
+                            "checkpoint was rendered with different settings: {}",
+                            state.key
+                        );
+                        println!("Resuming at {} samples per pixel", state.samples_per_pixel);
+                        state
+                    }
+                    None => Checkpoint::new(key, new_film()),
+                };
+                for (name, pixels) in &layers {
+                    state.film.set_aov(name, pixels.clone());
+                }
+                if settings.checkpoint_path.is_some() {
+                    checkpoint::catch_interrupt();
+                }
 
-    for t in threads {
-        t.join().unwrap();
-    }
+                let (film, samples_taken) = render_tiles(
+                    scene.deref(),
+                    integrator,
+                    camera,
+                    sampler,
+                    &settings,
+                    state,
+                    |image| {
+                        for output in &outputs {
+                            write_image(&output.path, image, width, height, &output.settings);
+                        }
+                    },
+                );
+                (film.image(), film.light_images(), samples_taken)
+            }
+        }
+    };
 
     let loop_dur = std::time::Instant::now() - time_before_loop;
-    let normal_data = collect_normals(world.deref(), camera, width, height);
-    let albedo_data = collect_albedo(world.deref(), camera, width, height);
-    write_image_flipped("beauty.png", &image.lock().unwrap(), width, height);
-    write_image_flipped("normal.png", &normal_data, width, height);
-    write_image_flipped("albedo.png", &albedo_data, width, height);
+    layers.extend(light_layers);
+    for output in &outputs {
+        write_layers(&output.path, &image, &layers, width, height, &output.settings);
+    }
 
     println!("Render took {} seconds", loop_dur.as_secs_f64());
     println!("Used {} threads", num_threads);
-    println!("Used {} Samples", samples_per_pixel);
+    println!("Used {} Samples", samples_taken);
+    println!("Used {} integrator", integrator_name);
     println!("Image size {}x{}", width, height);
     println!("Done!");
 }
@@ -1,16 +1,47 @@
 use crate::color::{self, Color};
-use crate::helpers::random_float;
 use crate::hittable::HitRecord;
 use crate::maths::{Ray, Vec3};
+use crate::sampler::Sampler;
+use crate::spectrum::{SampledSpectrum, SampledWavelengths};
 
 pub type MaterialPtr = std::sync::Arc<dyn Material>;
 
 pub trait Material: Sync + Send {
-    fn scatter(&self, ray: Ray, hit: &HitRecord) -> Option<(Color, Ray)>;
+    fn scatter(&self, ray: Ray, hit: &HitRecord, sampler: &mut dyn Sampler)
+        -> Option<(Color, Ray)>;
     fn emitted(&self) -> Color {
         color::BLACK
     }
     fn albedo(&self) -> Color;
+
+    // BSDF times cosine and the pdf scatter() would pick `dir` with,
+    // None for specular materials that cannot be evaluated for light sampling
+    fn bsdf(&self, _ray: &Ray, _hit: &HitRecord, _dir: Vec3) -> Option<(Color, f32)> {
+        None
+    }
+
+    // scatter() for the spectral mode. Materials that scatter each wavelength in a
+    // different direction follow the hero wavelength and terminate the others.
+    fn scatter_spectral(
+        &self,
+        ray: Ray,
+        hit: &HitRecord,
+        wavelengths: &mut SampledWavelengths,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(SampledSpectrum, Ray)> {
+        let (attenuation, scattered) = self.scatter(ray, hit, sampler)?;
+        Some((SampledSpectrum::from_albedo(attenuation, wavelengths), scattered))
+    }
+
+    fn bsdf_spectral(
+        &self,
+        _ray: &Ray,
+        _hit: &HitRecord,
+        _dir: Vec3,
+        _wavelengths: &SampledWavelengths,
+    ) -> Option<(SampledSpectrum, f32)> {
+        None
+    }
 }
 
 pub struct Lambertian {
@@ -24,8 +55,8 @@ impl Lambertian {
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, _: Ray, hit: &HitRecord) -> Option<(Color, Ray)> {
-        let mut scatter_direction = hit.normal + Vec3::random_unit_vector();
+    fn scatter(&self, _: Ray, hit: &HitRecord, sampler: &mut dyn Sampler) -> Option<(Color, Ray)> {
+        let mut scatter_direction = hit.normal + Vec3::random_unit_vector(sampler);
         if scatter_direction.length2() < f32::EPSILON {
             scatter_direction = hit.normal;
         }
@@ -37,6 +68,24 @@ impl Material for Lambertian {
     fn albedo(&self) -> Color {
         self.albedo
     }
+
+    fn bsdf(&self, _: &Ray, hit: &HitRecord, dir: Vec3) -> Option<(Color, f32)> {
+        let cosine = f32::max(0.0, Vec3::dot(dir.normalized(), hit.normal));
+        let pdf = cosine * std::f32::consts::FRAC_1_PI;
+        Some((self.albedo * pdf, pdf))
+    }
+
+    fn bsdf_spectral(
+        &self,
+        _: &Ray,
+        hit: &HitRecord,
+        dir: Vec3,
+        wavelengths: &SampledWavelengths,
+    ) -> Option<(SampledSpectrum, f32)> {
+        let cosine = f32::max(0.0, Vec3::dot(dir.normalized(), hit.normal));
+        let pdf = cosine * std::f32::consts::FRAC_1_PI;
+        Some((SampledSpectrum::from_albedo(self.albedo, wavelengths) * pdf, pdf))
+    }
 }
 
 pub struct Metal {
@@ -56,12 +105,13 @@ impl Metal {
 }
 
 impl Material for Metal {
-    fn scatter(&self, ray: Ray, hit: &HitRecord) -> Option<(Color, Ray)> {
+    fn scatter(&self, ray: Ray, hit: &HitRecord, sampler: &mut dyn Sampler)
+        -> Option<(Color, Ray)> {
         let reflected = Vec3::reflect(ray.dir.normalized(), hit.normal);
 
         let scattered = Ray::new(
             hit.point,
-            reflected + Vec3::inside_unit_sphere() * self.fuzz,
+            reflected + Vec3::inside_unit_sphere(sampler) * self.fuzz,
         );
         if Vec3::dot(scattered.dir, hit.normal) > 0.0 {
             return Some((self.albedo, scattered));
@@ -75,17 +125,66 @@ impl Material for Metal {
     }
 }
 
+// Index of refraction as a function of wavelength in nanometers
+#[derive(Clone, Copy, Debug)]
+pub enum Ior {
+    Constant(f32),
+    // a + b / lambda^2 with lambda in micrometers
+    Cauchy { a: f32, b: f32 },
+    // Sellmeier coefficients, c in square micrometers
+    Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+impl Ior {
+    // Wavelength RGB rendering uses, the sodium d-line glasses are usually quoted at
+    const REFERENCE_WAVELENGTH: f32 = 587.6;
+
+    // Schott N-BK7 crown glass
+    pub fn bk7() -> Ior {
+        Ior::Sellmeier {
+            b: [1.039_612, 0.231_792_34, 1.010_469_5],
+            c: [0.006_000_699, 0.020_017_914, 103.560_65],
+        }
+    }
+
+    // Schott SF11 dense flint glass, strongly dispersive
+    pub fn sf11() -> Ior {
+        Ior::Sellmeier {
+            b: [1.737_596_9, 0.313_747_35, 1.898_781],
+            c: [0.013_188_707, 0.062_306_814, 155.236_3],
+        }
+    }
+
+    pub fn at(&self, lambda: f32) -> f32 {
+        let micrometers = lambda * 1e-3;
+        let lambda2 = micrometers * micrometers;
+        match *self {
+            Ior::Constant(ior) => ior,
+            Ior::Cauchy { a, b } => a + b / lambda2,
+            Ior::Sellmeier { b, c } => {
+                let sum: f32 = (0..3).map(|i| b[i] * lambda2 / (lambda2 - c[i])).sum();
+                f32::sqrt(1.0 + sum)
+            }
+        }
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        !matches!(self, Ior::Constant(_))
+    }
+}
+
 pub struct Dieletric {
     albedo: Color,
-    index_of_refraction: f32,
+    ior: Ior,
 }
 
 impl Dieletric {
     pub fn create(albedo: Color, index_of_refraction: f32) -> MaterialPtr {
-        std::sync::Arc::new(Dieletric {
-            albedo,
-            index_of_refraction,
-        })
+        Dieletric::with_ior(albedo, Ior::Constant(index_of_refraction))
+    }
+
+    pub fn with_ior(albedo: Color, ior: Ior) -> MaterialPtr {
+        std::sync::Arc::new(Dieletric { albedo, ior })
     }
 
     fn reflectance(cosine: f32, ref_idx: f32) -> f32 {
@@ -94,14 +193,18 @@ impl Dieletric {
 
         r0 + (1.0 - r0) * f32::powi(1.0 - cosine, 5)
     }
-}
 
-impl Material for Dieletric {
-    fn scatter(&self, ray: Ray, hit: &HitRecord) -> Option<(Color, Ray)> {
+    fn scatter_with_ior(
+        &self,
+        ray: Ray,
+        hit: &HitRecord,
+        index_of_refraction: f32,
+        sampler: &mut dyn Sampler,
+    ) -> Ray {
         let refraction_ratio = if hit.front_face {
-            1.0 / self.index_of_refraction
+            1.0 / index_of_refraction
         } else {
-            self.index_of_refraction
+            index_of_refraction
         };
 
         let unit_dir = ray.dir.normalized();
@@ -110,21 +213,43 @@ impl Material for Dieletric {
 
         let cannot_refract = refraction_ratio * sin_theta > 1.0;
         let direction = if cannot_refract
-            || Dieletric::reflectance(cos_theta, refraction_ratio) > random_float(0.0..1.0)
+            || Dieletric::reflectance(cos_theta, refraction_ratio) > sampler.next_1d()
         {
             unit_dir.reflect(hit.normal)
         } else {
             unit_dir.refract(hit.normal, refraction_ratio)
         };
 
-        let scattered = Ray::new(hit.point, direction);
+        Ray::new(hit.point, direction)
+    }
+}
 
+impl Material for Dieletric {
+    fn scatter(&self, ray: Ray, hit: &HitRecord, sampler: &mut dyn Sampler)
+        -> Option<(Color, Ray)> {
+        let index_of_refraction = self.ior.at(Ior::REFERENCE_WAVELENGTH);
+        let scattered = self.scatter_with_ior(ray, hit, index_of_refraction, sampler);
         Some((self.albedo, scattered))
     }
 
     fn albedo(&self) -> Color {
         self.albedo
     }
+
+    fn scatter_spectral(
+        &self,
+        ray: Ray,
+        hit: &HitRecord,
+        wavelengths: &mut SampledWavelengths,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(SampledSpectrum, Ray)> {
+        if self.ior.is_dispersive() {
+            wavelengths.terminate_secondary();
+        }
+        let index_of_refraction = self.ior.at(wavelengths.hero());
+        let scattered = self.scatter_with_ior(ray, hit, index_of_refraction, sampler);
+        Some((SampledSpectrum::from_albedo(self.albedo, wavelengths), scattered))
+    }
 }
 
 pub struct DiffuseLight {
@@ -138,7 +263,7 @@ impl DiffuseLight {
 }
 
 impl Material for DiffuseLight {
-    fn scatter(&self, _: Ray, _: &HitRecord) -> Option<(Color, Ray)> {
+    fn scatter(&self, _: Ray, _: &HitRecord, _: &mut dyn Sampler) -> Option<(Color, Ray)> {
         None
     }
 
@@ -1,6 +1,6 @@
 use std::ops::*;
 
-use rand::Rng;
+use crate::sampler::Sampler;
 
 #[derive(Debug, Default, Clone, Copy)]
 pub struct Vec3 {
@@ -29,6 +29,31 @@ impl Ray {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct Onb {
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
+}
+
+impl Onb {
+    pub fn from_w(n: Vec3) -> Onb {
+        let w = n.normalized();
+        let a = if f32::abs(w.x) > 0.9 {
+            Vec3::up()
+        } else {
+            Vec3::right()
+        };
+        let v = Vec3::cross(w, a).normalized();
+        let u = Vec3::cross(w, v);
+        Onb { u, v, w }
+    }
+
+    pub fn local(&self, a: Vec3) -> Vec3 {
+        self.u * a.x + self.v * a.y + self.w * a.z
+    }
+}
+
 pub fn clamp<T: PartialOrd>(min:T, max:T, val:T ) -> T {
     if val < min {
         return min;
@@ -68,37 +93,17 @@ impl Vec3 {
         }
     }
 
-    pub fn inside_unit_sphere() -> Vec3 {
-        let random_vector = || {
-            let mut rng = rand::thread_rng();
-            let x = rng.gen_range(-1.0..1.0);
-            let y = rng.gen_range(-1.0..1.0);
-            let z = rng.gen_range(-1.0..1.0);
-            Vec3{x,y,z}
-        };
-
-        loop {
-            let test = random_vector();
-            if test.length2() < 1.0 {
-                return test
-            }
-        }   
+    // Warps from two or three uniform numbers rather than rejection sampling, so that
+    // every call consumes a fixed number of sample dimensions
+    pub fn inside_unit_sphere(sampler: &mut dyn Sampler) -> Vec3 {
+        let radius = f32::cbrt(sampler.next_1d());
+        Vec3::random_unit_vector(sampler) * radius
     }
 
-    pub fn on_unit_disc() -> Vec3 {
-        let random_vector = || {
-            let mut rng = rand::thread_rng();
-            let x = rng.gen_range(-1.0..1.0);
-            let y = rng.gen_range(-1.0..1.0);
-            Vec3{x,y,z:0.0}
-        };
-
-        loop {
-            let test = random_vector();
-            if test.length2() < 1.0 {
-                return test
-            }
-        }   
+    pub fn on_unit_disc(sampler: &mut dyn Sampler) -> Vec3 {
+        let radius = f32::sqrt(sampler.next_1d());
+        let phi = 2.0 * std::f32::consts::PI * sampler.next_1d();
+        Vec3{x: radius * phi.cos(), y: radius * phi.sin(), z: 0.0}
     }
 
     pub fn reflect(self, normal:Vec3) -> Vec3 {
@@ -113,12 +118,15 @@ impl Vec3 {
         r_out_perp + r_out_parallel
     }
 
-    pub fn random_unit_vector() -> Vec3 {
-        Vec3::inside_unit_sphere().normalized()
+    pub fn random_unit_vector(sampler: &mut dyn Sampler) -> Vec3 {
+        let z = 2.0 * sampler.next_1d() - 1.0;
+        let phi = 2.0 * std::f32::consts::PI * sampler.next_1d();
+        let r = f32::sqrt(f32::max(0.0, 1.0 - z * z));
+        Vec3{x: r * phi.cos(), y: r * phi.sin(), z}
     }
 
-    pub fn random_in_hemisphere(normal:Vec3) -> Vec3 {
-        let in_unit_sphere = Vec3::inside_unit_sphere();
+    pub fn random_in_hemisphere(normal:Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let in_unit_sphere = Vec3::inside_unit_sphere(sampler);
         if Vec3::dot(in_unit_sphere, normal) < 0.0 {
             return -in_unit_sphere;
         }
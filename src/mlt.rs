@@ -0,0 +1,309 @@
+use core::f32;
+use std::f32::consts::PI;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::camera::Camera;
+use crate::color::{Color, BLACK};
+use crate::distribution::Distribution1D;
+use crate::film::SplatBuffer;
+use crate::integrator::{Integrator, PathTracer};
+use crate::sampler::{hash, seeded_rng, Sampler, SamplerPtr};
+use crate::scene::Scene;
+
+// Primary sample space MLT (Kelemen et al. 2002), following pbrt's MLTIntegrator.
+// The path tracer is evaluated on a stream of primary samples in [0, 1) that Markov
+// chains mutate, either with small gaussian perturbations or with independent large
+// steps. The image brightness is recovered from a bootstrap pass of ordinary paths.
+pub struct MltSettings {
+    pub mutations_per_pixel: i32,
+    pub bootstrap_samples: usize,
+    pub chains: usize,
+    pub large_step_probability: f32,
+    pub sigma: f32,
+    pub max_depth: i32,
+    pub num_threads: i32,
+    pub seed: u64,
+}
+
+#[derive(Clone, Copy)]
+struct PrimarySample {
+    value: f32,
+    backup: f32,
+    last_modified: u64,
+    modified_backup: u64,
+}
+
+#[derive(Clone)]
+struct MltSampler {
+    rng: StdRng,
+    samples: Vec<PrimarySample>,
+    index: usize,
+    iteration: u64,
+    last_large_step: u64,
+    large_step: bool,
+    sigma: f32,
+    large_step_probability: f32,
+}
+
+impl MltSampler {
+    // Samplers with the same seed start out on the same path
+    fn new(seed: u64, sigma: f32, large_step_probability: f32) -> MltSampler {
+        MltSampler {
+            rng: StdRng::seed_from_u64(seed),
+            samples: Vec::new(),
+            index: 0,
+            iteration: 0,
+            last_large_step: 0,
+            large_step: true,
+            sigma,
+            large_step_probability,
+        }
+    }
+
+    fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<f32>() < self.large_step_probability;
+        self.index = 0;
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    fn reject(&mut self) {
+        for sample in self.samples.iter_mut() {
+            if sample.last_modified == self.iteration {
+                sample.value = sample.backup;
+                sample.last_modified = sample.modified_backup;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    fn next(&mut self) -> f32 {
+        // Dimensions no earlier state used are independent of the chain so far
+        if self.index >= self.samples.len() {
+            let value = self.rng.gen();
+            self.samples.push(PrimarySample {
+                value,
+                backup: value,
+                last_modified: self.iteration,
+                modified_backup: self.iteration.saturating_sub(1),
+            });
+            self.index += 1;
+            return value;
+        }
+        let sample = &mut self.samples[self.index];
+        self.index += 1;
+
+        // Samples are mutated lazily, the first time the current iteration asks for them
+        if sample.last_modified < self.last_large_step {
+            sample.value = self.rng.gen();
+            sample.last_modified = self.last_large_step;
+        }
+
+        sample.backup = sample.value;
+        sample.modified_backup = sample.last_modified;
+        if self.large_step {
+            sample.value = self.rng.gen();
+        } else {
+            // Catch up on all the small steps skipped since the sample was last used
+            let small_steps = (self.iteration - sample.last_modified) as f32;
+            let sigma = self.sigma * f32::sqrt(small_steps);
+            sample.value += sigma * normal_sample(&mut self.rng);
+            sample.value -= f32::floor(sample.value);
+        }
+        sample.last_modified = self.iteration;
+        sample.value
+    }
+}
+
+// Box-Muller
+fn normal_sample(rng: &mut StdRng) -> f32 {
+    let u1 = 1.0 - rng.gen::<f32>();
+    let u2 = rng.gen::<f32>();
+    f32::sqrt(-2.0 * f32::ln(u1)) * f32::cos(2.0 * PI * u2)
+}
+
+// Hands the primary samples to the integrator like any other sampler. Chains choose their
+// pixel themselves, so starting a pixel sample only goes back to the first dimension.
+impl Sampler for MltSampler {
+    fn start_pixel_sample(&mut self, _: i32, _: i32, _: u32) {
+        self.index = 0;
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        self.next()
+    }
+
+    fn samples_per_pixel(&self) -> u32 {
+        1
+    }
+
+    fn clone_sampler(&self) -> SamplerPtr {
+        Box::new(self.clone())
+    }
+}
+
+struct PathSample {
+    x: i32,
+    y: i32,
+    radiance: Color,
+    // Scalar contribution the chains are distributed by
+    importance: f32,
+}
+
+struct Renderer<'a> {
+    scene: &'a Scene,
+    camera: Camera,
+    width: i32,
+    height: i32,
+    integrator: PathTracer,
+}
+
+impl Renderer<'_> {
+    // Traces one camera path using the sampler's current primary samples
+    fn evaluate(&self, sampler: &mut MltSampler) -> PathSample {
+        let px = sampler.next() * self.width as f32;
+        let py = sampler.next() * self.height as f32;
+        let ray = self.camera.get_ray(
+            px / (self.width as f32 - 1.0),
+            py / (self.height as f32 - 1.0),
+            sampler,
+        );
+        let mut splats = SplatBuffer::new(self.width, self.height);
+        let radiance = self
+            .integrator
+            .radiance(ray, self.scene, &mut splats, sampler);
+        PathSample {
+            x: i32::min(px as i32, self.width - 1),
+            y: i32::min(py as i32, self.height - 1),
+            radiance,
+            importance: radiance.luminance(),
+        }
+    }
+
+    fn splat(&self, image: &mut [Color], sample: &PathSample, weight: f32) {
+        if weight > 0.0 && sample.importance > 0.0 {
+            let index = (sample.y * self.width + sample.x) as usize;
+            image[index] += sample.radiance * (weight / sample.importance);
+        }
+    }
+
+    fn run_chain(&self, image: &mut [Color], seed: u64, mutations: usize, settings: &MltSettings) {
+        let mut sampler = MltSampler::new(seed, settings.sigma, settings.large_step_probability);
+        let mut current = self.evaluate(&mut sampler);
+
+        for _ in 0..mutations {
+            sampler.start_iteration();
+            let proposed = self.evaluate(&mut sampler);
+
+            let accept = if current.importance > 0.0 {
+                f32::min(1.0, proposed.importance / current.importance)
+            } else {
+                1.0
+            };
+
+            // Both states contribute by their expected value rather than only the chosen one
+            self.splat(image, &proposed, accept);
+            self.splat(image, &current, 1.0 - accept);
+
+            if sampler.rng.gen::<f32>() < accept {
+                sampler.accept();
+                current = proposed;
+            } else {
+                sampler.reject();
+            }
+        }
+    }
+}
+
+pub fn render(
+    scene: &Scene,
+    camera: Camera,
+    width: i32,
+    height: i32,
+    settings: &MltSettings,
+) -> Vec<Color> {
+    let renderer = Renderer {
+        scene,
+        camera,
+        width,
+        height,
+        integrator: PathTracer {
+            max_depth: settings.max_depth,
+        },
+    };
+    let num_threads = usize::max(settings.num_threads as usize, 1);
+    let pixel_count = (width * height) as usize;
+
+    // Bootstrap: plain path samples estimate the normalization and seed the chains
+    let mut weights = vec![0.0; settings.bootstrap_samples];
+    std::thread::scope(|threads| {
+        let per_thread = settings.bootstrap_samples.div_ceil(num_threads);
+        for (chunk_index, chunk) in weights.chunks_mut(per_thread).enumerate() {
+            let renderer = &renderer;
+            threads.spawn(move || {
+                for (offset, weight) in chunk.iter_mut().enumerate() {
+                    let index = (chunk_index * per_thread + offset) as u64;
+                    let mut sampler = MltSampler::new(
+                        hash(&[settings.seed, index]),
+                        settings.sigma,
+                        settings.large_step_probability,
+                    );
+                    *weight = renderer.evaluate(&mut sampler).importance;
+                }
+            });
+        }
+    });
+
+    let bootstrap = Distribution1D::new(&weights);
+    let normalization = bootstrap.integral();
+    if weights.is_empty() || normalization <= 0.0 {
+        return vec![BLACK; pixel_count];
+    }
+
+    let total_mutations = settings.mutations_per_pixel.max(0) as usize * pixel_count;
+    let chains = usize::max(settings.chains, 1);
+    let mutations_per_chain = total_mutations.div_ceil(chains);
+    // Chains restart from bootstrap paths picked by their contribution
+    let mut rng = seeded_rng(&[settings.seed]);
+    let seeds: Vec<u64> = (0..chains)
+        .map(|_| {
+            let index = bootstrap.sample_continuous(rng.gen()).2 as u64;
+            hash(&[settings.seed, index])
+        })
+        .collect();
+
+    // Every chain splats into an image of its own and those are added up in chain order, so
+    // the result doesn't depend on the thread count. Chains run in waves of one per thread,
+    // which keeps no more images around than there are threads.
+    let mut image = vec![BLACK; pixel_count];
+    let mut chain_images = vec![vec![BLACK; pixel_count]; usize::min(num_threads, chains)];
+    for wave in seeds.chunks(chain_images.len()) {
+        std::thread::scope(|threads| {
+            for (chain_image, &seed) in chain_images.iter_mut().zip(wave) {
+                let renderer = &renderer;
+                threads.spawn(move || {
+                    chain_image.fill(BLACK);
+                    renderer.run_chain(chain_image, seed, mutations_per_chain, settings);
+                });
+            }
+        });
+        for chain_image in &chain_images[..wave.len()] {
+            for (pixel, &color) in image.iter_mut().zip(chain_image) {
+                *pixel += color;
+            }
+        }
+    }
+
+    let scale = normalization * pixel_count as f32 / (mutations_per_chain * chains) as f32;
+    for pixel in image.iter_mut() {
+        *pixel = *pixel * scale;
+    }
+    image
+}
@@ -0,0 +1,559 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use exr::prelude::{
+    f16, AnyChannel, AnyChannels, Compression, Encoding, FlatSamples, Image, Layer,
+    LayerAttributes, SmallVec, SpecificChannels, Vec2, WritableImage,
+};
+
+use crate::color::Color;
+use crate::helpers::write_image_flipped;
+use crate::tonemap::{Curve, ToneMapping};
+
+#[derive(Clone, Copy)]
+pub struct OutputSettings {
+    // Half floats for EXR, otherwise full 32-bit floats
+    pub half: bool,
+    pub compression: Compression,
+    pub tone_mapping: ToneMapping,
+}
+
+impl OutputSettings {
+    pub fn new(
+        precision: &str,
+        compression: &str,
+        tone_mapping: ToneMapping,
+    ) -> Option<OutputSettings> {
+        let half = match precision {
+            "half" => true,
+            "float" => false,
+            _ => return None,
+        };
+        let compression = match compression {
+            "none" => Compression::Uncompressed,
+            "zip" => Compression::ZIP16,
+            "piz" => Compression::PIZ,
+            _ => return None,
+        };
+        Some(OutputSettings {
+            half,
+            compression,
+            tone_mapping,
+        })
+    }
+}
+
+// A file the render is written to
+pub struct Output {
+    pub path: String,
+    pub settings: OutputSettings,
+}
+
+// Builds the outputs from the output flags in command line order. Every -o adds a file, and
+// -o-tonemap, -o-exposure and -o-white-balance override the tone mapping of the file before
+// them. Paths are taken as they are, so they can contain any character.
+pub fn parse_outputs(
+    flags: &[(String, String)],
+    defaults: OutputSettings,
+) -> Result<Vec<Output>, String> {
+    let mut outputs: Vec<Output> = Vec::new();
+    for (flag, value) in flags {
+        if flag == "-o" {
+            outputs.push(Output {
+                path: value.clone(),
+                settings: defaults,
+            });
+            continue;
+        }
+
+        let output = match outputs.last_mut() {
+            Some(output) => output,
+            None => return Err(format!("{} has to follow an -o", flag)),
+        };
+        let tone_mapping = &mut output.settings.tone_mapping;
+        let number = || {
+            value
+                .parse::<f32>()
+                .map_err(|_| format!("invalid number {} for {}", value, flag))
+        };
+        match flag.as_str() {
+            "-o-tonemap" => {
+                tone_mapping.curve = Curve::from_name(value)
+                    .ok_or_else(|| format!("unknown tone mapping curve {}", value))?
+            }
+            "-o-exposure" => tone_mapping.exposure = number()?,
+            "-o-white-balance" => tone_mapping.set_white_balance(number()?),
+            _ => return Err(format!("unknown output option {}", flag)),
+        }
+    }
+    Ok(outputs)
+}
+
+// Writes the image in the format of the file extension: OpenEXR, PFM or Radiance HDR keep
+// the full range of the image, anything else is tone mapped to an 8-bit PNG
+pub fn write_image(
+    path: &str,
+    image: &[Color],
+    width: i32,
+    height: i32,
+    settings: &OutputSettings,
+) {
+    let tone_mapping = &settings.tone_mapping;
+    let result = match extension(path).as_deref() {
+        Some("exr") => write_exr(
+            path,
+            &tone_mapping.linear_image(image),
+            width,
+            height,
+            settings,
+        ),
+        Some("pfm") => write_pfm(path, &tone_mapping.linear_image(image), width, height),
+        Some("hdr") => write_hdr(path, &tone_mapping.linear_image(image), width, height),
+        _ => {
+            let image: Vec<Color> = image
+                .iter()
+                .map(|&color| tone_mapping.display(color))
+                .collect();
+            write_image_flipped(path, &image, width, height);
+            return;
+        }
+    };
+    match result {
+        Ok(()) => println!("Saved file {}", path),
+        Err(error) => println!("Failed to save {}: {}", path, error),
+    }
+}
+
+// Layers that formats without layers still get, as files of their own
+const SIDE_FILES: [&str; 2] = ["normal", "albedo"];
+
+// Writes the image with extra layers like normals or the contribution of each light. An EXR
+// gets them all as named channels of one file, other formats only the normal and albedo as
+// files next to it.
+pub fn write_layers(
+    path: &str,
+    image: &[Color],
+    layers: &[(String, Vec<Color>)],
+    width: i32,
+    height: i32,
+    settings: &OutputSettings,
+) {
+    if extension(path).as_deref() != Some("exr") {
+        write_image(path, image, width, height, settings);
+        for (name, pixels) in layers {
+            if SIDE_FILES.contains(&name.as_str()) {
+                let settings = OutputSettings {
+                    tone_mapping: ToneMapping::new(),
+                    ..*settings
+                };
+                write_image(&aov_path(path, name), pixels, width, height, &settings);
+            }
+        }
+        return;
+    }
+
+    let image = settings.tone_mapping.linear_image(image);
+    let layers: Vec<(String, Vec<Color>)> = layers
+        .iter()
+        .map(|(name, pixels)| {
+            let tone_mapping = layer_tone_mapping(name, &settings.tone_mapping);
+            (name.clone(), tone_mapping.linear_image(pixels))
+        })
+        .collect();
+    match write_multilayer_exr(path, &image, &layers, width, height, settings) {
+        Ok(()) => println!("Saved file {}", path),
+        Err(error) => println!("Failed to save {}: {}", path, error),
+    }
+}
+
+// Light layers add up to the beauty and are tone mapped like it, the others hold data like
+// normals that is written as it is
+fn layer_tone_mapping(name: &str, tone_mapping: &ToneMapping) -> ToneMapping {
+    match name.starts_with("light_") {
+        true => *tone_mapping,
+        false => ToneMapping::new(),
+    }
+}
+
+fn extension(path: &str) -> Option<String> {
+    let extension = Path::new(path).extension()?.to_str()?;
+    Some(extension.to_ascii_lowercase())
+}
+
+// Channels a layer is stored as, and whether it always needs full precision. Geometric
+// layers are vectors or single values rather than colors.
+fn layer_channels(name: &str) -> (&'static [&'static str], bool) {
+    match name {
+        "normal" | "position" => (&["X", "Y", "Z"], true),
+        "depth" => (&["Z"], true),
+        "object_id" => (&["ID"], true),
+        _ => (&["R", "G", "B"], false),
+    }
+}
+
+fn write_multilayer_exr(
+    path: &str,
+    image: &[Color],
+    layers: &[(String, Vec<Color>)],
+    width: i32,
+    height: i32,
+    settings: &OutputSettings,
+) -> io::Result<()> {
+    let (columns, rows) = (width as usize, height as usize);
+    let channel = |name: String, pixels: &[Color], component: usize, full: bool| {
+        let values = (0..rows).flat_map(|y| {
+            (0..columns).map(move |x| {
+                let color = pixel(pixels, width, x, y);
+                [color.r, color.g, color.b][component]
+            })
+        });
+        let samples = if settings.half && !full {
+            FlatSamples::F16(values.map(f16::from_f32).collect())
+        } else {
+            FlatSamples::F32(values.collect())
+        };
+        AnyChannel::new(name.as_str(), samples)
+    };
+
+    // The beauty goes into the unprefixed R, G and B so every reader shows it by default
+    let mut channels = SmallVec::new();
+    for (component, name) in ["R", "G", "B"].iter().enumerate() {
+        channels.push(channel(name.to_string(), image, component, false));
+    }
+    for (layer, pixels) in layers {
+        let (names, full) = layer_channels(layer);
+        for (component, name) in names.iter().enumerate() {
+            let name = format!("{}.{}", layer, name);
+            channels.push(channel(name, pixels, component, full));
+        }
+    }
+
+    let encoding = Encoding {
+        compression: settings.compression,
+        ..Encoding::default()
+    };
+    let layer = Layer::new(
+        (columns, rows),
+        LayerAttributes::default(),
+        encoding,
+        AnyChannels::sort(channels),
+    );
+    Image::from_layer(layer)
+        .write()
+        .to_file(path)
+        .map_err(|error| io::Error::other(error.to_string()))
+}
+
+// Pixel (x, y) counted from the top left, in the same orientation as write_image_flipped
+fn pixel(image: &[Color], width: i32, x: usize, y: usize) -> Color {
+    image[image.len() - 1 - (y * width as usize + x)]
+}
+
+fn write_exr(
+    path: &str,
+    image: &[Color],
+    width: i32,
+    height: i32,
+    settings: &OutputSettings,
+) -> io::Result<()> {
+    let size = (width as usize, height as usize);
+    let encoding = Encoding {
+        compression: settings.compression,
+        ..Encoding::default()
+    };
+    let result = if settings.half {
+        let channels = SpecificChannels::rgb(|Vec2(x, y)| {
+            let color = pixel(image, width, x, y);
+            (
+                f16::from_f32(color.r),
+                f16::from_f32(color.g),
+                f16::from_f32(color.b),
+            )
+        });
+        let layer = Layer::new(size, LayerAttributes::default(), encoding, channels);
+        Image::from_layer(layer).write().to_file(path)
+    } else {
+        let channels = SpecificChannels::rgb(|Vec2(x, y)| {
+            let color = pixel(image, width, x, y);
+            (color.r, color.g, color.b)
+        });
+        let layer = Layer::new(size, LayerAttributes::default(), encoding, channels);
+        Image::from_layer(layer).write().to_file(path)
+    };
+    result.map_err(|error| io::Error::other(error.to_string()))
+}
+
+// Portable float map, little endian with the rows from the bottom up
+fn write_pfm(path: &str, image: &[Color], width: i32, height: i32) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write!(out, "PF\n{} {}\n-1.0\n", width, height)?;
+    for y in (0..height as usize).rev() {
+        for x in 0..width as usize {
+            let color = pixel(image, width, x, y);
+            for value in [color.r, color.g, color.b] {
+                out.write_all(&value.to_le_bytes())?;
+            }
+        }
+    }
+    out.flush()
+}
+
+// Radiance RGBE with run-length encoded scanlines
+fn write_hdr(path: &str, image: &[Color], width: i32, height: i32) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write!(
+        out,
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        height, width
+    )?;
+
+    let mut scanline = vec![[0u8; 4]; width as usize];
+    for y in 0..height as usize {
+        for (x, rgbe) in scanline.iter_mut().enumerate() {
+            *rgbe = to_rgbe(pixel(image, width, x, y));
+        }
+
+        // Run-length encoding only exists for these widths, others are written flat
+        if !(8..=0x7fff).contains(&width) {
+            for rgbe in &scanline {
+                out.write_all(rgbe)?;
+            }
+            continue;
+        }
+        out.write_all(&[2, 2, (width >> 8) as u8, (width & 0xff) as u8])?;
+        for channel in 0..4 {
+            let values: Vec<u8> = scanline.iter().map(|rgbe| rgbe[channel]).collect();
+            write_rle(&mut out, &values)?;
+        }
+    }
+    out.flush()
+}
+
+// Shared exponent so the largest component keeps 8 bits of mantissa
+fn to_rgbe(color: Color) -> [u8; 4] {
+    let max = f32::max(color.r, f32::max(color.g, color.b));
+    if !max.is_finite() || max <= 1e-32 {
+        return [0; 4];
+    }
+    let exponent = max.log2().floor() as i32 + 1;
+    let scale = 256.0 / 2f32.powi(exponent);
+    let byte = |value: f32| f32::min(f32::max(value, 0.0) * scale, 255.0) as u8;
+    [
+        byte(color.r),
+        byte(color.g),
+        byte(color.b),
+        (exponent + 128) as u8,
+    ]
+}
+
+// Runs of 4 or more equal bytes as (128 + length, value), everything else as
+// (length, bytes...)
+fn write_rle(out: &mut impl Write, values: &[u8]) -> io::Result<()> {
+    let run_at = |start: usize| {
+        let mut length = 1;
+        while start + length < values.len()
+            && length < 127
+            && values[start + length] == values[start]
+        {
+            length += 1;
+        }
+        length
+    };
+
+    let mut index = 0;
+    while index < values.len() {
+        let run = run_at(index);
+        if run >= 4 {
+            out.write_all(&[128 + run as u8, values[index]])?;
+            index += run;
+            continue;
+        }
+
+        let start = index;
+        while index < values.len() && index - start < 128 && run_at(index) < 4 {
+            index += 1;
+        }
+        out.write_all(&[(index - start) as u8])?;
+        out.write_all(&values[start..index])?;
+    }
+    Ok(())
+}
+
+// Path of an extra image like the normals, next to the main output and in the same format
+pub fn aov_path(output_path: &str, name: &str) -> String {
+    let path = Path::new(output_path);
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    path.with_file_name(format!("{}.{}", name, extension.unwrap_or("png")))
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(args: &[(&str, &str)]) -> Vec<(String, String)> {
+        args.iter()
+            .map(|(flag, value)| (flag.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn defaults() -> OutputSettings {
+        OutputSettings::new("float", "zip", ToneMapping::new()).unwrap()
+    }
+
+    #[test]
+    fn paths_are_taken_as_they_are() {
+        let args = flags(&[("-o", "C:\\renders\\a:b.exr"), ("-o", "one,two.png")]);
+        let outputs = parse_outputs(&args, defaults()).unwrap();
+        let paths: Vec<&str> = outputs.iter().map(|output| output.path.as_str()).collect();
+        assert_eq!(paths, ["C:\\renders\\a:b.exr", "one,two.png"]);
+    }
+
+    #[test]
+    fn options_apply_to_the_output_before_them() {
+        let args = flags(&[
+            ("-o", "full.exr"),
+            ("-o", "preview.png"),
+            ("-o-tonemap", "aces"),
+            ("-o-exposure", "1.5"),
+        ]);
+        let outputs = parse_outputs(&args, defaults()).unwrap();
+        assert!(matches!(
+            outputs[0].settings.tone_mapping.curve,
+            Curve::Clamp
+        ));
+        assert_eq!(outputs[0].settings.tone_mapping.exposure, 0.0);
+        assert!(matches!(
+            outputs[1].settings.tone_mapping.curve,
+            Curve::Aces
+        ));
+        assert_eq!(outputs[1].settings.tone_mapping.exposure, 1.5);
+    }
+
+    #[test]
+    fn unknown_options_are_rejected() {
+        let rejected = [
+            flags(&[("-o", "a.png"), ("-o-gamma", "2.2")]),
+            flags(&[("-o", "a.png"), ("-o-tonemap", "sepia")]),
+            flags(&[("-o", "a.png"), ("-o-exposure", "bright")]),
+            flags(&[("-o-exposure", "1.0"), ("-o", "a.png")]),
+        ];
+        for args in &rejected {
+            assert!(parse_outputs(args, defaults()).is_err());
+        }
+    }
+
+    fn temp_path(name: &str) -> String {
+        let file = format!("{}-{}", std::process::id(), name);
+        std::env::temp_dir()
+            .join(file)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    // Written the way the renderer stores images: the last entry is the top left pixel
+    fn image_of(rows: &[&[Color]]) -> Vec<Color> {
+        rows.iter()
+            .flat_map(|row| row.iter())
+            .rev()
+            .copied()
+            .collect()
+    }
+
+    fn read_back(path: &str) -> Vec<u8> {
+        let bytes = std::fs::read(path);
+        std::fs::remove_file(path).unwrap();
+        bytes.unwrap()
+    }
+
+    #[test]
+    fn rgbe_shares_the_exponent_of_the_largest_component() {
+        assert_eq!(to_rgbe(Color::new(1.0, 0.5, 0.25)), [128, 64, 32, 129]);
+        assert_eq!(to_rgbe(Color::new(0.0, 3.0, 0.0)), [0, 192, 0, 130]);
+        assert_eq!(to_rgbe(Color::new(0.0, 0.0, 0.0)), [0; 4]);
+        assert_eq!(to_rgbe(Color::new(f32::INFINITY, 0.0, 0.0)), [0; 4]);
+    }
+
+    #[test]
+    fn rle_writes_runs_and_literals() {
+        let mut out = Vec::new();
+        write_rle(&mut out, &[5, 5, 5, 5, 5, 1, 2, 3, 7, 7]).unwrap();
+        assert_eq!(out, [133, 5, 5, 1, 2, 3, 7, 7]);
+
+        // Runs stop at 127 and literals at 128 bytes
+        let mut out = Vec::new();
+        write_rle(&mut out, &[9; 130]).unwrap();
+        assert_eq!(out, [255, 9, 3, 9, 9, 9]);
+        let literal: Vec<u8> = (0..130).map(|i| i as u8).collect();
+        let mut out = Vec::new();
+        write_rle(&mut out, &literal).unwrap();
+        assert_eq!(out[0], 128);
+        assert_eq!(out[1..129], literal[..128]);
+        assert_eq!(out[129..], [2, 128, 129]);
+    }
+
+    #[test]
+    fn hdr_scanlines_are_run_length_encoded_per_channel() {
+        let color = Color::new(1.0, 0.5, 0.25);
+        let black = Color::new(0.0, 0.0, 0.0);
+        let image = image_of(&[&[black, color, color, color, color, color, color, color]]);
+        let path = temp_path("scanline.hdr");
+        write_hdr(&path, &image, 8, 1).unwrap();
+
+        let mut expected = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 8\n".to_vec();
+        expected.extend([2, 2, 0, 8]);
+        for value in [128, 64, 32, 129] {
+            expected.extend([1, 0, 128 + 7, value]);
+        }
+        assert_eq!(read_back(&path), expected);
+    }
+
+    #[test]
+    fn pfm_is_little_endian_from_the_bottom_row_up() {
+        let color = |value: f32| Color::new(value, value + 0.25, value + 0.5);
+        let image = image_of(&[&[color(1.0), color(2.0)], &[color(3.0), color(4.0)]]);
+        let path = temp_path("rows.pfm");
+        write_pfm(&path, &image, 2, 2).unwrap();
+
+        let mut expected = b"PF\n2 2\n-1.0\n".to_vec();
+        for value in [3.0, 4.0, 1.0, 2.0] {
+            for component in [value, value + 0.25, value + 0.5] {
+                expected.extend(f32::to_le_bytes(component));
+            }
+        }
+        assert_eq!(read_back(&path), expected);
+    }
+
+    #[test]
+    fn exr_keeps_pixels_in_place() {
+        let image = image_of(&[
+            &[Color::new(0.5, 1.0, 1.5), Color::new(2.0, 2.5, 3.0)],
+            &[Color::new(0.25, 0.0, 4.0), Color::new(8.0, 16.0, 0.125)],
+        ]);
+        for precision in ["float", "half"] {
+            let settings = OutputSettings::new(precision, "zip", ToneMapping::new()).unwrap();
+            let path = temp_path(&format!("{}.exr", precision));
+            write_exr(&path, &image, 2, 2, &settings).unwrap();
+            let read = exr::prelude::read_first_rgba_layer_from_file(
+                &path,
+                |size, _| vec![[0.0; 3]; size.width() * size.height()],
+                |pixels: &mut Vec<[f32; 3]>, position, (r, g, b, _): (f32, f32, f32, f32)| {
+                    pixels[position.y() * 2 + position.x()] = [r, g, b]
+                },
+            );
+            std::fs::remove_file(&path).unwrap();
+            let pixels = read.unwrap().layer_data.channel_data.pixels;
+            assert_eq!(
+                pixels,
+                [
+                    [0.5, 1.0, 1.5],
+                    [2.0, 2.5, 3.0],
+                    [0.25, 0.0, 4.0],
+                    [8.0, 16.0, 0.125]
+                ]
+            );
+        }
+    }
+}
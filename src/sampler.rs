@@ -0,0 +1,455 @@
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::blue_noise;
+
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+// Dimensions of the Halton sequence, later dimensions of a sample are independent
+const HALTON_DIMENSIONS: usize = 256;
+
+// Source of the uniform numbers for one pixel sample. Each call to next_1d returns the
+// next dimension of the current sample, the renderer consumes them in a fixed order:
+// pixel position first, then the lens and every bounce's BSDF and light choices.
+// Consecutive pairs of dimensions are stratified together where the sampler supports it.
+// Samples depend only on the seed, the pixel, the sample index and the dimension, so
+// renders are reproducible whichever thread traces which pixel.
+pub trait Sampler: Send {
+    // Moves to sample `sample_index` of pixel (x, y) and restarts at its first dimension
+    fn start_pixel_sample(&mut self, x: i32, y: i32, sample_index: u32);
+
+    fn next_1d(&mut self) -> f32;
+
+    fn samples_per_pixel(&self) -> u32;
+
+    // Sampler for another render thread
+    fn clone_sampler(&self) -> SamplerPtr;
+}
+
+pub type SamplerPtr = Box<dyn Sampler>;
+
+pub fn create(name: &str, samples_per_pixel: i32, seed: u64) -> Option<SamplerPtr> {
+    let samples_per_pixel = u32::max(samples_per_pixel as u32, 1);
+    match name {
+        "independent" => Some(Box::new(IndependentSampler::new(samples_per_pixel, seed))),
+        "stratified" => Some(Box::new(StratifiedSampler::new(samples_per_pixel, seed))),
+        "halton" => Some(Box::new(HaltonSampler::new(samples_per_pixel, seed))),
+        "sobol" => Some(Box::new(SobolSampler::new(samples_per_pixel, seed))),
+        "bluenoise" => Some(Box::new(BlueNoiseSampler::new(samples_per_pixel, seed))),
+        _ => None,
+    }
+}
+
+// Uniform random numbers hashed from the sample and dimension, plain white noise
+#[derive(Clone)]
+pub struct IndependentSampler {
+    samples_per_pixel: u32,
+    seed: u64,
+    sample_hash: u64,
+    dimension: u64,
+}
+
+impl IndependentSampler {
+    pub fn new(samples_per_pixel: u32, seed: u64) -> IndependentSampler {
+        IndependentSampler {
+            samples_per_pixel,
+            seed,
+            sample_hash: 0,
+            dimension: 0,
+        }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, x: i32, y: i32, sample_index: u32) {
+        self.sample_hash = hash(&[self.seed, x as u64, y as u64, sample_index as u64]);
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        self.dimension += 1;
+        uniform_from_hash(mix_bits(self.sample_hash ^ self.dimension))
+    }
+
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
+
+    fn clone_sampler(&self) -> SamplerPtr {
+        Box::new(self.clone())
+    }
+}
+
+// Jittered samples in a grid of samples_per_pixel strata for every pair of dimensions.
+// Each pair visits the strata in its own shuffled order so that pairs stay uncorrelated.
+#[derive(Clone)]
+pub struct StratifiedSampler {
+    samples_per_pixel: u32,
+    x_strata: u32,
+    y_strata: u32,
+    seed: u64,
+    pixel_hash: u64,
+    sample_hash: u64,
+    sample_index: u32,
+    dimension: u32,
+}
+
+impl StratifiedSampler {
+    pub fn new(samples_per_pixel: u32, seed: u64) -> StratifiedSampler {
+        // The most square grid with exactly samples_per_pixel cells
+        let mut x_strata = f32::sqrt(samples_per_pixel as f32) as u32;
+        while !samples_per_pixel.is_multiple_of(x_strata) {
+            x_strata -= 1;
+        }
+        StratifiedSampler {
+            samples_per_pixel,
+            x_strata,
+            y_strata: samples_per_pixel / x_strata,
+            seed,
+            pixel_hash: 0,
+            sample_hash: 0,
+            sample_index: 0,
+            dimension: 0,
+        }
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, x: i32, y: i32, sample_index: u32) {
+        self.pixel_hash = hash(&[self.seed, x as u64, y as u64]);
+        self.sample_hash = hash(&[self.pixel_hash, sample_index as u64]);
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+
+        let jitter = uniform_from_hash(mix_bits(self.sample_hash ^ dimension as u64));
+        if self.sample_index >= self.samples_per_pixel {
+            return jitter;
+        }
+
+        let pair_hash = mix_bits(self.pixel_hash ^ (dimension / 2) as u64) as u32;
+        let stratum = permutation_element(self.sample_index, self.samples_per_pixel, pair_hash);
+        let value = if dimension.is_multiple_of(2) {
+            ((stratum % self.x_strata) as f32 + jitter) / self.x_strata as f32
+        } else {
+            ((stratum / self.x_strata) as f32 + jitter) / self.y_strata as f32
+        };
+        f32::min(value, ONE_MINUS_EPSILON)
+    }
+
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
+
+    fn clone_sampler(&self) -> SamplerPtr {
+        Box::new(self.clone())
+    }
+}
+
+// Halton sequence with one prime base per dimension, decorrelated between pixels by
+// Owen scrambling the digits with a per-pixel hash
+#[derive(Clone)]
+pub struct HaltonSampler {
+    samples_per_pixel: u32,
+    primes: Vec<u32>,
+    seed: u64,
+    pixel_hash: u64,
+    sample_index: u32,
+    dimension: usize,
+}
+
+impl HaltonSampler {
+    pub fn new(samples_per_pixel: u32, seed: u64) -> HaltonSampler {
+        HaltonSampler {
+            samples_per_pixel,
+            primes: first_primes(HALTON_DIMENSIONS),
+            seed,
+            pixel_hash: 0,
+            sample_index: 0,
+            dimension: 0,
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, x: i32, y: i32, sample_index: u32) {
+        self.pixel_hash = hash(&[self.seed, x as u64, y as u64]);
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+
+        match self.primes.get(dimension) {
+            Some(&base) => owen_scrambled_radical_inverse(
+                base,
+                self.sample_index as u64,
+                mix_bits(self.pixel_hash ^ dimension as u64),
+            ),
+            None => uniform_from_hash(hash(&[
+                self.pixel_hash,
+                self.sample_index as u64,
+                dimension as u64,
+            ])),
+        }
+    }
+
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
+
+    fn clone_sampler(&self) -> SamplerPtr {
+        Box::new(self.clone())
+    }
+}
+
+// The first two dimensions of the Sobol sequence, Owen scrambled, padded out to every
+// pair of dimensions by shuffling the sample order per pair as in pbrt-v4's
+// PaddedSobolSampler. Best with a power of two samples per pixel.
+#[derive(Clone)]
+pub struct SobolSampler {
+    samples_per_pixel: u32,
+    seed: u64,
+    pixel_hash: u64,
+    sample_index: u32,
+    dimension: u32,
+}
+
+impl SobolSampler {
+    pub fn new(samples_per_pixel: u32, seed: u64) -> SobolSampler {
+        SobolSampler {
+            samples_per_pixel,
+            seed,
+            pixel_hash: 0,
+            sample_index: 0,
+            dimension: 0,
+        }
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, x: i32, y: i32, sample_index: u32) {
+        self.pixel_hash = hash(&[self.seed, x as u64, y as u64]);
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+
+        let pair_hash = mix_bits(self.pixel_hash ^ (dimension / 2) as u64);
+        let index =
+            permutation_element(self.sample_index, self.samples_per_pixel, pair_hash as u32);
+        let (x, y) = sobol_2d(index);
+        let bits = if dimension.is_multiple_of(2) { x } else { y };
+        let scrambled = owen_scramble(bits, mix_bits(pair_hash ^ dimension as u64) as u32);
+        (scrambled >> 8) as f32 / (1 << 24) as f32
+    }
+
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
+
+    fn clone_sampler(&self) -> SamplerPtr {
+        Box::new(self.clone())
+    }
+}
+
+// The same padded Sobol samples in every pixel, Cranley-Patterson rotated by a tiled
+// blue-noise mask (Georgiev and Fajardo 2016). Neighbouring pixels get very different
+// rotations, which turns the error at low sample counts into high frequency noise. Each
+// dimension reads the mask at its own offset so that dimensions stay uncorrelated.
+#[derive(Clone)]
+pub struct BlueNoiseSampler {
+    samples_per_pixel: u32,
+    seed: u64,
+    x: i32,
+    y: i32,
+    sample_index: u32,
+    dimension: u32,
+}
+
+impl BlueNoiseSampler {
+    pub fn new(samples_per_pixel: u32, seed: u64) -> BlueNoiseSampler {
+        // Generates the mask up front rather than in the first render thread
+        blue_noise::mask();
+        BlueNoiseSampler {
+            samples_per_pixel,
+            seed,
+            x: 0,
+            y: 0,
+            sample_index: 0,
+            dimension: 0,
+        }
+    }
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn start_pixel_sample(&mut self, x: i32, y: i32, sample_index: u32) {
+        self.x = x;
+        self.y = y;
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+
+        let pair_hash = hash(&[self.seed, (dimension / 2) as u64]);
+        let index =
+            permutation_element(self.sample_index, self.samples_per_pixel, pair_hash as u32);
+        let (x, y) = sobol_2d(index);
+        let bits = if dimension.is_multiple_of(2) { x } else { y };
+        let scrambled = owen_scramble(bits, mix_bits(pair_hash ^ dimension as u64) as u32);
+        let sample = (scrambled >> 8) as f32 / (1 << 24) as f32;
+
+        let offset = mix_bits(self.seed ^ dimension as u64);
+        let rotation = blue_noise::value(
+            self.x + (offset & 0xffff) as i32,
+            self.y + (offset >> 16 & 0xffff) as i32,
+        );
+        let value = sample + rotation;
+        f32::min(value - f32::floor(value), ONE_MINUS_EPSILON)
+    }
+
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
+
+    fn clone_sampler(&self) -> SamplerPtr {
+        Box::new(self.clone())
+    }
+}
+
+// Sobol points of the first two dimensions as 0.32 fixed point
+fn sobol_2d(index: u32) -> (u32, u32) {
+    let mut y = 0;
+    let mut direction = 1u32 << 31;
+    let mut bits = index;
+    while bits != 0 {
+        if bits & 1 != 0 {
+            y ^= direction;
+        }
+        bits >>= 1;
+        direction ^= direction >> 1;
+    }
+    (index.reverse_bits(), y)
+}
+
+// Nested uniform scrambling of a 0.32 fixed point value (Burley 2020)
+fn owen_scramble(value: u32, seed: u32) -> u32 {
+    let mut v = value.reverse_bits();
+    v ^= v.wrapping_mul(0x3d20adea);
+    v = v.wrapping_add(seed);
+    v = v.wrapping_mul((seed >> 16) | 1);
+    v ^= v.wrapping_mul(0x05526c56);
+    v ^= v.wrapping_mul(0x53a22864);
+    v.reverse_bits()
+}
+
+// Radical inverse of `index` in `base` with every digit permuted depending on the
+// digits before it
+fn owen_scrambled_radical_inverse(base: u32, index: u64, hash: u64) -> f32 {
+    let inv_base = 1.0 / base as f32;
+    let mut inv_base_m = 1.0;
+    let mut reversed_digits = 0u64;
+    let mut index = index;
+    while 1.0 - (base - 1) as f32 * inv_base_m < 1.0 {
+        let next = index / base as u64;
+        let digit = (index - next * base as u64) as u32;
+        let digit_hash = mix_bits(hash ^ reversed_digits) as u32;
+        let digit = permutation_element(digit, base, digit_hash);
+        reversed_digits = reversed_digits * base as u64 + digit as u64;
+        inv_base_m *= inv_base;
+        index = next;
+    }
+    f32::min(inv_base_m * reversed_digits as f32, ONE_MINUS_EPSILON)
+}
+
+// Element `i` of a pseudo-random permutation of 0..len chosen by `seed` (Kensler 2013).
+// Indices past the end are left in place.
+fn permutation_element(i: u32, len: u32, seed: u32) -> u32 {
+    if i >= len {
+        return i;
+    }
+
+    let mut w = len - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    let p = seed;
+    let mut i = i;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < len {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % len
+}
+
+fn first_primes(count: usize) -> Vec<u32> {
+    let mut primes: Vec<u32> = Vec::with_capacity(count);
+    let mut candidate = 2;
+    while primes.len() < count {
+        if primes.iter().all(|prime| candidate % prime != 0) {
+            primes.push(candidate);
+        }
+        candidate += 1;
+    }
+    primes
+}
+
+// 64 bit integer mixer, pbrt's MixBits
+pub fn mix_bits(v: u64) -> u64 {
+    let mut v = v;
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5d329728ea185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81dadef4bc2dd44d);
+    v ^= v >> 33;
+    v
+}
+
+pub fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e3779b97f4a7c15, |hash, &value| {
+        mix_bits(hash ^ mix_bits(value))
+    })
+}
+
+fn uniform_from_hash(bits: u64) -> f32 {
+    (bits >> 40) as f32 / (1u64 << 24) as f32
+}
+
+// Random number generator for work outside the samplers, seeded from a hash of `key`
+pub fn seeded_rng(key: &[u64]) -> StdRng {
+    StdRng::seed_from_u64(hash(key))
+}
@@ -0,0 +1,25 @@
+use crate::bvh::Bvh;
+use crate::color::Color;
+use crate::environment::EnvironmentPtr;
+use crate::light::Lights;
+use crate::maths::Vec3;
+
+pub enum Background {
+    Constant(Color),
+    Environment(EnvironmentPtr),
+}
+
+impl Background {
+    pub fn radiance(&self, dir: Vec3) -> Color {
+        match self {
+            Background::Constant(color) => *color,
+            Background::Environment(map) => map.radiance(dir),
+        }
+    }
+}
+
+pub struct Scene {
+    pub world: Bvh,
+    pub lights: Lights,
+    pub background: Background,
+}
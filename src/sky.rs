@@ -0,0 +1,203 @@
+use std::f32::consts::PI;
+
+use crate::color::Color;
+use crate::environment::Environment;
+use crate::maths::{clamp, Onb, Vec3};
+use crate::sampler::Sampler;
+
+// Preetham et al. "A Practical Analytic Model for Daylight" luminances are in kcd/m^2,
+// this brings a clear noon sky to roughly the range of the other lights in the scene
+const KCD_TO_SCENE: f32 = 0.02;
+// Luminance of the sun disk seen from outside the atmosphere, in kcd/m^2
+const SUN_LUMINANCE: f32 = 1.6e6;
+const SUN_ANGULAR_DIAMETER: f32 = 0.53;
+
+struct Perez {
+    a: f32,
+    b: f32,
+    c: f32,
+    d: f32,
+    e: f32,
+}
+
+impl Perez {
+    fn eval(&self, cos_theta: f32, gamma: f32) -> f32 {
+        let cos_gamma = f32::cos(gamma);
+        (1.0 + self.a * f32::exp(self.b / cos_theta))
+            * (1.0 + self.c * f32::exp(self.d * gamma) + self.e * cos_gamma * cos_gamma)
+    }
+}
+
+pub struct PreethamSky {
+    sun_dir: Vec3,
+    sun_radiance: Color,
+    cos_sun_half_angle: f32,
+    intensity: f32,
+    perez: [Perez; 3],
+    // Zenith Yxy divided by the Perez function at the zenith
+    zenith: [f32; 3],
+}
+
+impl PreethamSky {
+    // Sun elevation above the horizon and azimuth around +y in degrees,
+    // turbidity from ~2 (very clear) to ~10 (hazy)
+    pub fn new(elevation: f32, azimuth: f32, turbidity: f32, intensity: f32) -> PreethamSky {
+        let elevation = f32::to_radians(elevation);
+        let azimuth = f32::to_radians(azimuth);
+        let sun_dir = Vec3::new(
+            elevation.cos() * azimuth.cos(),
+            elevation.sin(),
+            elevation.cos() * azimuth.sin(),
+        );
+
+        let t = turbidity;
+        let theta_s = f32::acos(clamp(0.0, 1.0, sun_dir.y));
+        let theta_s2 = theta_s * theta_s;
+        let theta_s3 = theta_s2 * theta_s;
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_y = (4.0453 * t - 4.9710) * f32::tan(chi) - 0.2155 * t + 2.4192;
+        let zenith_x = t * t * (0.00166 * theta_s3 - 0.00375 * theta_s2 + 0.00209 * theta_s)
+            + t * (-0.02903 * theta_s3 + 0.06377 * theta_s2 - 0.03202 * theta_s + 0.00394)
+            + (0.11693 * theta_s3 - 0.21196 * theta_s2 + 0.06052 * theta_s + 0.25886);
+        let zenith_y_chroma = t * t * (0.00275 * theta_s3 - 0.00610 * theta_s2 + 0.00317 * theta_s)
+            + t * (-0.04214 * theta_s3 + 0.08970 * theta_s2 - 0.04153 * theta_s + 0.00516)
+            + (0.15346 * theta_s3 - 0.26756 * theta_s2 + 0.06670 * theta_s + 0.26688);
+
+        let perez = [
+            Perez {
+                a: 0.1787 * t - 1.4630,
+                b: -0.3554 * t + 0.4275,
+                c: -0.0227 * t + 5.3251,
+                d: 0.1206 * t - 2.5771,
+                e: -0.0670 * t + 0.3703,
+            },
+            Perez {
+                a: -0.0193 * t - 0.2592,
+                b: -0.0665 * t + 0.0008,
+                c: -0.0004 * t + 0.2125,
+                d: -0.0641 * t - 0.8989,
+                e: -0.0033 * t + 0.0452,
+            },
+            Perez {
+                a: -0.0167 * t - 0.2608,
+                b: -0.0950 * t + 0.0092,
+                c: -0.0079 * t + 0.2102,
+                d: -0.0441 * t - 1.6537,
+                e: -0.0109 * t + 0.0529,
+            },
+        ];
+
+        let zenith = [
+            f32::max(0.0, zenith_y) / perez[0].eval(1.0, theta_s),
+            zenith_x / perez[1].eval(1.0, theta_s),
+            zenith_y_chroma / perez[2].eval(1.0, theta_s),
+        ];
+
+        PreethamSky {
+            sun_dir,
+            sun_radiance: sun_transmittance(theta_s, t) * SUN_LUMINANCE,
+            cos_sun_half_angle: f32::cos(f32::to_radians(0.5 * SUN_ANGULAR_DIAMETER)),
+            intensity: intensity * KCD_TO_SCENE,
+            perez,
+            zenith,
+        }
+    }
+
+    fn sun_visible(&self) -> bool {
+        self.sun_dir.y > 0.0
+    }
+
+    fn sky_radiance(&self, dir: Vec3) -> Color {
+        // Below the horizon the sky is continued with its horizon value
+        let cos_theta = f32::max(dir.y, 0.01);
+        let gamma = f32::acos(clamp(-1.0, 1.0, Vec3::dot(dir, self.sun_dir)));
+
+        let big_y = self.zenith[0] * self.perez[0].eval(cos_theta, gamma);
+        let x = self.zenith[1] * self.perez[1].eval(cos_theta, gamma);
+        let y = self.zenith[2] * self.perez[2].eval(cos_theta, gamma);
+        if y <= 0.0 {
+            return crate::color::BLACK;
+        }
+
+        xyz_to_linear_srgb(x / y * big_y, big_y, (1.0 - x - y) / y * big_y)
+    }
+
+    fn sun_probability(&self) -> f32 {
+        if self.sun_visible() {
+            0.5
+        } else {
+            0.0
+        }
+    }
+
+    fn sun_cone_pdf(&self) -> f32 {
+        1.0 / (2.0 * PI * (1.0 - self.cos_sun_half_angle))
+    }
+}
+
+impl Environment for PreethamSky {
+    fn radiance(&self, dir: Vec3) -> Color {
+        let dir = dir.normalized();
+        let mut radiance = self.sky_radiance(dir);
+        if self.sun_visible() && Vec3::dot(dir, self.sun_dir) >= self.cos_sun_half_angle {
+            radiance += self.sun_radiance;
+        }
+        radiance * self.intensity
+    }
+
+    // Mixture of the sun cone and the uniform upper hemisphere
+    fn random_direction(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        let r1 = sampler.next_1d();
+        let r2 = sampler.next_1d();
+        let phi = 2.0 * PI * r1;
+
+        let (z, frame) = if sampler.next_1d() < self.sun_probability() {
+            (1.0 + r2 * (self.cos_sun_half_angle - 1.0), Onb::from_w(self.sun_dir))
+        } else {
+            (r2, Onb::from_w(Vec3::up()))
+        };
+
+        let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - z * z));
+        frame.local(Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, z))
+    }
+
+    fn pdf_value(&self, dir: Vec3) -> f32 {
+        let dir = dir.normalized();
+        let sun_probability = self.sun_probability();
+
+        let mut pdf = 0.0;
+        if dir.y > 0.0 {
+            pdf += (1.0 - sun_probability) / (2.0 * PI);
+        }
+        if sun_probability > 0.0 && Vec3::dot(dir, self.sun_dir) >= self.cos_sun_half_angle {
+            pdf += sun_probability * self.sun_cone_pdf();
+        }
+        pdf
+    }
+}
+
+// Rayleigh and aerosol (Angstrom) extinction along the sun path, per channel
+fn sun_transmittance(theta_s: f32, turbidity: f32) -> Color {
+    let zenith_degrees = f32::to_degrees(theta_s);
+    let air_mass = 1.0
+        / (f32::cos(theta_s) + 0.15 * f32::powf(f32::max(93.885 - zenith_degrees, 0.01), -1.253));
+    let beta = 0.04608 * turbidity - 0.04586;
+
+    // Representative wavelengths for r, g and b in micrometers
+    let channel = |lambda: f32| {
+        let rayleigh = 0.008735 * f32::powf(lambda, -4.08);
+        let aerosol = beta * f32::powf(lambda, -1.3);
+        f32::exp(-air_mass * (rayleigh + aerosol))
+    };
+
+    Color::new(channel(0.65), channel(0.57), channel(0.475))
+}
+
+fn xyz_to_linear_srgb(x: f32, y: f32, z: f32) -> Color {
+    Color::new(
+        f32::max(0.0, 3.2406 * x - 1.5372 * y - 0.4986 * z),
+        f32::max(0.0, -0.9689 * x + 1.8758 * y + 0.0415 * z),
+        f32::max(0.0, 0.0557 * x - 0.2040 * y + 1.0570 * z),
+    )
+}
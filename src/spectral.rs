@@ -0,0 +1,111 @@
+use core::f32;
+
+use crate::color::Color;
+use crate::film::SplatBuffer;
+use crate::hittable::{HitRecord, Hittable};
+use crate::integrator::{
+    emitted_radiance, miss_radiance, sample_light_radiance, Integrator, ROULETTE_START_DEPTH,
+};
+use crate::maths::Ray;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::spectrum::{SampledSpectrum, SampledWavelengths};
+
+// Path tracer carrying a hero wavelength and its rotations instead of RGB. Albedos and
+// emission are upsampled from the scene's RGB colors, the result is converted back to
+// linear sRGB through CIE XYZ.
+pub struct SpectralPathTracer {
+    pub max_depth: i32,
+}
+
+impl Integrator for SpectralPathTracer {
+    fn radiance(
+        &self,
+        ray: Ray,
+        scene: &Scene,
+        _: &mut SplatBuffer,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let mut wavelengths = SampledWavelengths::sample_visible(sampler.next_1d());
+        let radiance = trace_path(ray, scene, self.max_depth, &mut wavelengths, sampler);
+        radiance.to_rgb(&wavelengths)
+    }
+}
+
+fn trace_path(
+    ray: Ray,
+    scene: &Scene,
+    max_depth: i32,
+    wavelengths: &mut SampledWavelengths,
+    sampler: &mut dyn Sampler,
+) -> SampledSpectrum {
+    let mut radiance = SampledSpectrum::new(0.0);
+    let mut throughput = SampledSpectrum::new(1.0);
+    let mut ray = ray;
+    let mut bsdf_pdf: Option<f32> = None;
+
+    for depth in 0..max_depth {
+        // The MIS weights scale the RGB emission, which upsampling preserves
+        let hit = match scene.world.hit(0.01, f32::INFINITY, &ray) {
+            Some(hit) => hit,
+            None => {
+                let background = miss_radiance(ray, scene, bsdf_pdf);
+                radiance += throughput * SampledSpectrum::from_illuminant(background, wavelengths);
+                break;
+            }
+        };
+
+        let mat = &hit.material;
+        let emitted = emitted_radiance(ray, &hit, scene, bsdf_pdf);
+        radiance += throughput * SampledSpectrum::from_illuminant(emitted, wavelengths);
+
+        let (attenuation, scattered) = match mat.scatter_spectral(ray, &hit, wavelengths, sampler) {
+            Some(scatter) => scatter,
+            None => break,
+        };
+
+        bsdf_pdf = match mat.bsdf_spectral(&ray, &hit, scattered.dir, wavelengths) {
+            Some((_, scattered_pdf)) => {
+                radiance += throughput * sample_lights(ray, &hit, scene, wavelengths, sampler);
+                Some(scattered_pdf)
+            }
+            None => None,
+        };
+
+        throughput = throughput * attenuation;
+        ray = scattered;
+
+        if depth >= ROULETTE_START_DEPTH {
+            let survival = f32::min(throughput.max_component(), 0.95);
+            if survival <= 0.0 || sampler.next_1d() >= survival {
+                break;
+            }
+            throughput = throughput * (1.0 / survival);
+        }
+    }
+
+    radiance
+}
+
+fn sample_lights(
+    ray: Ray,
+    hit: &HitRecord,
+    scene: &Scene,
+    wavelengths: &SampledWavelengths,
+    sampler: &mut dyn Sampler,
+) -> SampledSpectrum {
+    let bsdf = |dir| {
+        let f = hit.material.bsdf_spectral(&ray, hit, dir, wavelengths);
+        f.map(|(f, _)| f).filter(|f| !f.is_black())
+    };
+    let scatter_pdf = |dir| {
+        let f = hit.material.bsdf_spectral(&ray, hit, dir, wavelengths);
+        f.map_or(0.0, |(_, pdf)| pdf)
+    };
+    let mut direct = SampledSpectrum::new(0.0);
+    let mut add = |f: SampledSpectrum, radiance: Color, _| {
+        direct += SampledSpectrum::from_illuminant(radiance, wavelengths) * f;
+    };
+    sample_light_radiance(hit.point, scene, &bsdf, &scatter_pdf, &mut add, sampler);
+    direct
+}
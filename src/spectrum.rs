@@ -0,0 +1,484 @@
+use core::f32;
+use std::sync::OnceLock;
+
+use crate::color::Color;
+
+// Visible range the spectral mode samples and fits spectra over, in nanometers
+pub const LAMBDA_MIN: f32 = 360.0;
+pub const LAMBDA_MAX: f32 = 830.0;
+// Wavelengths traced together along one path, the first is the hero wavelength
+pub const SPECTRUM_SAMPLES: usize = 4;
+
+// Resolution of the RGB to spectrum coefficient table in each dimension
+const TABLE_RESOLUTION: usize = 32;
+// Spacing of the wavelengths the table is fitted against
+const FIT_STEP: f32 = 5.0;
+
+// Spectral quantity at the wavelengths of a SampledWavelengths
+#[derive(Clone, Copy, Debug)]
+pub struct SampledSpectrum {
+    pub values: [f32; SPECTRUM_SAMPLES],
+}
+
+impl SampledSpectrum {
+    pub fn new(value: f32) -> SampledSpectrum {
+        SampledSpectrum {
+            values: [value; SPECTRUM_SAMPLES],
+        }
+    }
+
+    // Reflectance spectrum for an RGB albedo, components are clamped to [0, 1]
+    pub fn from_albedo(rgb: Color, wavelengths: &SampledWavelengths) -> SampledSpectrum {
+        let rgb = Color::new(
+            rgb.r.clamp(0.0, 1.0),
+            rgb.g.clamp(0.0, 1.0),
+            rgb.b.clamp(0.0, 1.0),
+        );
+        let sigmoid = tables().coefficients.lookup(rgb);
+        SampledSpectrum {
+            values: wavelengths.lambda.map(|lambda| sigmoid.eval(lambda)),
+        }
+    }
+
+    // Unbounded spectrum for RGB emission and other radiometric quantities. The color is
+    // normalized before the fit, which keeps the spectrum linear in the color's scale.
+    pub fn from_illuminant(rgb: Color, wavelengths: &SampledWavelengths) -> SampledSpectrum {
+        let max = rgb.max_component();
+        if max <= 0.0 {
+            return SampledSpectrum::new(0.0);
+        }
+
+        let scale = 2.0 * max;
+        let sigmoid = tables().coefficients.lookup(rgb * (1.0 / scale));
+        SampledSpectrum {
+            values: wavelengths
+                .lambda
+                .map(|lambda| scale * sigmoid.eval(lambda)),
+        }
+    }
+
+    pub fn max_component(self) -> f32 {
+        self.values
+            .iter()
+            .fold(0.0, |max, &value| f32::max(max, value))
+    }
+
+    pub fn is_black(self) -> bool {
+        self.values.iter().all(|&value| value <= 0.0)
+    }
+
+    pub fn average(self) -> f32 {
+        self.values.iter().sum::<f32>() / SPECTRUM_SAMPLES as f32
+    }
+
+    // Monte Carlo estimate of the linear sRGB color of the spectrum
+    pub fn to_rgb(self, wavelengths: &SampledWavelengths) -> Color {
+        let tables = tables();
+        let mut xyz = [0.0; 3];
+        for i in 0..SPECTRUM_SAMPLES {
+            if wavelengths.pdf[i] <= 0.0 {
+                continue;
+            }
+            let weight = self.values[i] / (wavelengths.pdf[i] * SPECTRUM_SAMPLES as f32);
+            let cmf = matching_functions(wavelengths.lambda[i]);
+            for (sum, value) in xyz.iter_mut().zip(cmf) {
+                *sum += weight * value;
+            }
+        }
+
+        let m = &tables.xyz_to_rgb;
+        let row =
+            |i: usize| (m[i][0] * xyz[0] + m[i][1] * xyz[1] + m[i][2] * xyz[2]) / tables.y_integral;
+        Color::new(row(0), row(1), row(2))
+    }
+}
+
+impl std::ops::Add for SampledSpectrum {
+    type Output = SampledSpectrum;
+
+    fn add(self, rhs: SampledSpectrum) -> SampledSpectrum {
+        let mut values = self.values;
+        for (value, other) in values.iter_mut().zip(rhs.values) {
+            *value += other;
+        }
+        SampledSpectrum { values }
+    }
+}
+
+impl std::ops::AddAssign for SampledSpectrum {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+impl std::ops::Mul for SampledSpectrum {
+    type Output = SampledSpectrum;
+
+    fn mul(self, rhs: SampledSpectrum) -> SampledSpectrum {
+        let mut values = self.values;
+        for (value, other) in values.iter_mut().zip(rhs.values) {
+            *value *= other;
+        }
+        SampledSpectrum { values }
+    }
+}
+
+impl std::ops::Mul<f32> for SampledSpectrum {
+    type Output = SampledSpectrum;
+
+    fn mul(self, rhs: f32) -> SampledSpectrum {
+        SampledSpectrum {
+            values: self.values.map(|value| value * rhs),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SampledWavelengths {
+    pub lambda: [f32; SPECTRUM_SAMPLES],
+    pub pdf: [f32; SPECTRUM_SAMPLES],
+}
+
+impl SampledWavelengths {
+    // Hero wavelength importance sampled towards the visible range (pbrt's
+    // SampleVisibleWavelengths), the others evenly rotated from it
+    pub fn sample_visible(u: f32) -> SampledWavelengths {
+        let mut lambda = [0.0; SPECTRUM_SAMPLES];
+        let mut pdf = [0.0; SPECTRUM_SAMPLES];
+        for i in 0..SPECTRUM_SAMPLES {
+            let u = (u + i as f32 / SPECTRUM_SAMPLES as f32).fract();
+            lambda[i] = 538.0 - 138.888_89 * f32::atanh(0.856_910_6 - 1.827_502 * u);
+            let cosh = f32::cosh(0.0072 * (lambda[i] - 538.0));
+            pdf[i] = 0.003_939_804 / (cosh * cosh);
+        }
+        SampledWavelengths { lambda, pdf }
+    }
+
+    pub fn hero(&self) -> f32 {
+        self.lambda[0]
+    }
+
+    // Keeps only the hero wavelength, for paths whose direction depends on it
+    pub fn terminate_secondary(&mut self) {
+        if self.secondary_terminated() {
+            return;
+        }
+        for pdf in self.pdf.iter_mut().skip(1) {
+            *pdf = 0.0;
+        }
+        self.pdf[0] /= SPECTRUM_SAMPLES as f32;
+    }
+
+    pub fn secondary_terminated(&self) -> bool {
+        self.pdf[1..].iter().all(|&pdf| pdf == 0.0)
+    }
+}
+
+// Analytic multi-lobe fit of the CIE 1931 color matching functions
+// (Wyman, Sloan and Shirley 2013)
+fn matching_functions(lambda: f32) -> [f32; 3] {
+    let lobe = |mean: f32, sigma_low: f32, sigma_high: f32| {
+        let sigma = if lambda < mean { sigma_low } else { sigma_high };
+        let t = (lambda - mean) / sigma;
+        f32::exp(-0.5 * t * t)
+    };
+
+    [
+        1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7)
+            - 0.065 * lobe(501.1, 20.4, 26.2),
+        0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+        1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8),
+    ]
+}
+
+// sigmoid(c0 t^2 + c1 t + c2) over t = 0..1 across the visible range (Jakob and Hanika 2019)
+#[derive(Clone, Copy, Default)]
+struct SigmoidPolynomial {
+    c: [f32; 3],
+}
+
+impl SigmoidPolynomial {
+    fn eval(&self, lambda: f32) -> f32 {
+        let t = (lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN);
+        sigmoid(self.c[0] * t * t + self.c[1] * t + self.c[2])
+    }
+}
+
+fn sigmoid(x: f32) -> f32 {
+    if x.is_infinite() {
+        return if x > 0.0 { 1.0 } else { 0.0 };
+    }
+    0.5 + x / (2.0 * f32::sqrt(1.0 + x * x))
+}
+
+struct Tables {
+    // CIE XYZ to linear sRGB, white balanced so a constant spectrum maps to gray
+    xyz_to_rgb: [[f32; 3]; 3],
+    y_integral: f32,
+    coefficients: CoefficientTable,
+}
+
+fn tables() -> &'static Tables {
+    static TABLES: OnceLock<Tables> = OnceLock::new();
+    TABLES.get_or_init(Tables::new)
+}
+
+impl Tables {
+    fn new() -> Tables {
+        let srgb = [
+            [3.2406, -1.5372, -0.4986],
+            [-0.9689, 1.8758, 0.0415],
+            [0.0557, -0.2040, 1.0570],
+        ];
+
+        let mut integral = [0.0; 3];
+        let mut lambda = LAMBDA_MIN;
+        while lambda <= LAMBDA_MAX {
+            for (sum, value) in integral.iter_mut().zip(matching_functions(lambda)) {
+                *sum += value;
+            }
+            lambda += 1.0;
+        }
+
+        // The spectral mode renders under an equal energy white point
+        let mut xyz_to_rgb = srgb;
+        for row in xyz_to_rgb.iter_mut() {
+            let white =
+                (row[0] * integral[0] + row[1] * integral[1] + row[2] * integral[2]) / integral[1];
+            for value in row.iter_mut() {
+                *value /= white;
+            }
+        }
+
+        let y_integral = integral[1];
+        Tables {
+            xyz_to_rgb,
+            y_integral,
+            coefficients: CoefficientTable::fit(&xyz_to_rgb, y_integral),
+        }
+    }
+}
+
+// Sigmoid coefficients indexed by the largest RGB component, its value and the other
+// two components relative to it
+struct CoefficientTable {
+    scale: Vec<f32>,
+    data: Vec<SigmoidPolynomial>,
+}
+
+impl CoefficientTable {
+    fn index(max_index: usize, z: usize, y: usize, x: usize) -> usize {
+        let res = TABLE_RESOLUTION;
+        ((max_index * res + z) * res + y) * res + x
+    }
+
+    fn fit(xyz_to_rgb: &[[f32; 3]; 3], y_integral: f32) -> CoefficientTable {
+        let res = TABLE_RESOLUTION;
+        let smoothstep = |x: f32| x * x * (3.0 - 2.0 * x);
+        let scale: Vec<f32> = (0..res)
+            .map(|k| smoothstep(smoothstep(k as f32 / (res - 1) as f32)))
+            .collect();
+
+        // RGB each fitting wavelength contributes per unit of reflectance
+        let mut samples = Vec::new();
+        let mut lambda = LAMBDA_MIN;
+        while lambda <= LAMBDA_MAX {
+            let cmf = matching_functions(lambda);
+            let rgb = xyz_to_rgb.map(|row| {
+                (row[0] * cmf[0] + row[1] * cmf[1] + row[2] * cmf[2]) * FIT_STEP / y_integral
+            });
+            let t = (lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN);
+            samples.push((t as f64, rgb.map(|value| value as f64)));
+            lambda += FIT_STEP;
+        }
+
+        let mut data = vec![SigmoidPolynomial::default(); 3 * res * res * res];
+        std::thread::scope(|threads| {
+            for (max_index, slice) in data.chunks_mut(res * res * res).enumerate() {
+                let (samples, scale) = (&samples, &scale);
+                threads.spawn(move || {
+                    for y in 0..res {
+                        for x in 0..res {
+                            let relative =
+                                (x as f32 / (res - 1) as f32, y as f32 / (res - 1) as f32);
+                            // Fits walk outwards from a moderate brightness, each starting
+                            // from its neighbour's solution
+                            let start = res / 5;
+                            let upwards: Vec<usize> = (start..res).collect();
+                            let downwards: Vec<usize> = (0..=start).rev().collect();
+                            for range in [upwards, downwards] {
+                                let mut coefficients = [0.0; 3];
+                                for z in range {
+                                    let mut target = [0.0; 3];
+                                    target[max_index] = scale[z] as f64;
+                                    target[(max_index + 1) % 3] = (relative.0 * scale[z]) as f64;
+                                    target[(max_index + 2) % 3] = (relative.1 * scale[z]) as f64;
+                                    coefficients = gauss_newton(samples, target, coefficients);
+                                    slice[CoefficientTable::index(0, z, y, x)] =
+                                        SigmoidPolynomial {
+                                            c: coefficients.map(|c| c as f32),
+                                        };
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        CoefficientTable { scale, data }
+    }
+
+    fn lookup(&self, rgb: Color) -> SigmoidPolynomial {
+        let rgb = [rgb.r, rgb.g, rgb.b];
+        if rgb[0] == rgb[1] && rgb[1] == rgb[2] {
+            let v = rgb[0];
+            let c = if v <= 0.0 {
+                f32::NEG_INFINITY
+            } else if v >= 1.0 {
+                f32::INFINITY
+            } else {
+                (v - 0.5) / f32::sqrt(v * (1.0 - v))
+            };
+            return SigmoidPolynomial { c: [0.0, 0.0, c] };
+        }
+
+        let max_index = if rgb[0] > rgb[1] {
+            if rgb[0] > rgb[2] {
+                0
+            } else {
+                2
+            }
+        } else if rgb[1] > rgb[2] {
+            1
+        } else {
+            2
+        };
+
+        let res = TABLE_RESOLUTION;
+        let z = rgb[max_index];
+        let x = rgb[(max_index + 1) % 3] / z * (res - 1) as f32;
+        let y = rgb[(max_index + 2) % 3] / z * (res - 1) as f32;
+
+        let zi = usize::min(
+            self.scale.partition_point(|&s| s <= z).saturating_sub(1),
+            res - 2,
+        );
+        let xi = usize::min(x as usize, res - 2);
+        let yi = usize::min(y as usize, res - 2);
+        let dx = x - xi as f32;
+        let dy = y - yi as f32;
+        let dz = (z - self.scale[zi]) / (self.scale[zi + 1] - self.scale[zi]);
+
+        let mut c = [0.0; 3];
+        for (corner, weight) in [
+            ((0, 0, 0), (1.0 - dz) * (1.0 - dy) * (1.0 - dx)),
+            ((0, 0, 1), (1.0 - dz) * (1.0 - dy) * dx),
+            ((0, 1, 0), (1.0 - dz) * dy * (1.0 - dx)),
+            ((0, 1, 1), (1.0 - dz) * dy * dx),
+            ((1, 0, 0), dz * (1.0 - dy) * (1.0 - dx)),
+            ((1, 0, 1), dz * (1.0 - dy) * dx),
+            ((1, 1, 0), dz * dy * (1.0 - dx)),
+            ((1, 1, 1), dz * dy * dx),
+        ] {
+            let entry = &self.data
+                [CoefficientTable::index(max_index, zi + corner.0, yi + corner.1, xi + corner.2)];
+            for (sum, value) in c.iter_mut().zip(entry.c) {
+                *sum += weight * value;
+            }
+        }
+        SigmoidPolynomial { c }
+    }
+}
+
+// Residual of the RGB a sigmoid polynomial reproduces against `target`, and its Jacobian
+fn fit_residual(
+    samples: &[(f64, [f64; 3])],
+    target: [f64; 3],
+    c: [f64; 3],
+) -> ([f64; 3], [[f64; 3]; 3]) {
+    let mut residual = [-target[0], -target[1], -target[2]];
+    let mut jacobian = [[0.0; 3]; 3];
+    for &(t, rgb) in samples {
+        let x = c[0] * t * t + c[1] * t + c[2];
+        let root = f64::sqrt(1.0 + x * x);
+        let value = 0.5 + x / (2.0 * root);
+        let derivative = 0.5 / (root * root * root);
+        let basis = [t * t, t, 1.0];
+        for i in 0..3 {
+            residual[i] += value * rgb[i];
+            for j in 0..3 {
+                jacobian[i][j] += derivative * basis[j] * rgb[i];
+            }
+        }
+    }
+    (residual, jacobian)
+}
+
+fn norm2(v: [f64; 3]) -> f64 {
+    v[0] * v[0] + v[1] * v[1] + v[2] * v[2]
+}
+
+// Gauss-Newton with step halving, so a fit never moves away from the target
+fn gauss_newton(samples: &[(f64, [f64; 3])], target: [f64; 3], initial: [f64; 3]) -> [f64; 3] {
+    let mut c = initial;
+    let (mut residual, mut jacobian) = fit_residual(samples, target, c);
+    for _ in 0..50 {
+        let error = norm2(residual);
+        if error < 1e-12 {
+            break;
+        }
+        let step = match solve3(jacobian, residual) {
+            Some(step) => step,
+            None => break,
+        };
+
+        let mut length = 1.0;
+        let mut improved = false;
+        for _ in 0..20 {
+            let next = [
+                c[0] - length * step[0],
+                c[1] - length * step[1],
+                c[2] - length * step[2],
+            ];
+            if next.iter().all(|value| value.is_finite()) {
+                let (next_residual, next_jacobian) = fit_residual(samples, target, next);
+                if norm2(next_residual) < error {
+                    c = next;
+                    residual = next_residual;
+                    jacobian = next_jacobian;
+                    improved = true;
+                    break;
+                }
+            }
+            length *= 0.5;
+        }
+        if !improved {
+            break;
+        }
+    }
+    c
+}
+
+// Cramer's rule
+fn solve3(m: [[f64; 3]; 3], b: [f64; 3]) -> Option<[f64; 3]> {
+    let det = |m: [[f64; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let d = det(m);
+    if d.abs() < 1e-15 {
+        return None;
+    }
+
+    let mut x = [0.0; 3];
+    for (column, value) in x.iter_mut().enumerate() {
+        let mut replaced = m;
+        for row in 0..3 {
+            replaced[row][column] = b[row];
+        }
+        *value = det(replaced) / d;
+    }
+    Some(x)
+}
@@ -0,0 +1,366 @@
+use core::f32;
+use std::f32::consts::PI;
+
+use crate::camera::Camera;
+use crate::color::{Color, BLACK, WHITE};
+use crate::hittable::{HitRecord, Hittable};
+use crate::integrator::estimate_direct;
+use crate::light::sample_disk;
+use crate::maths::{Ray, Vec3};
+use crate::sampler::{hash, IndependentSampler, Sampler};
+use crate::scene::Scene;
+
+// Stochastic progressive photon mapping (Hachisuka and Jensen 2009), following pbrt.
+// Every iteration traces one camera path per pixel to its first diffuse surface, shoots
+// `photons` photons into a hash grid of those visible points and shrinks each pixel's
+// gather radius by `alpha`.
+pub struct SppmSettings {
+    pub iterations: i32,
+    pub photons: usize,
+    pub initial_radius: f32,
+    pub alpha: f32,
+    pub max_depth: i32,
+    pub num_threads: i32,
+    pub seed: u64,
+}
+
+struct VisiblePoint {
+    point: Vec3,
+    ray: Ray,
+    hit: HitRecord,
+    beta: Color,
+}
+
+// Photons are traced in batches of this many, which bounds the deposits kept in memory
+const PHOTON_BATCH: usize = 1 << 16;
+
+struct Pixel {
+    radius: f32,
+    direct: Color,
+    photon_count: f32,
+    tau: Color,
+    visible: Option<VisiblePoint>,
+    phi: Color,
+    new_photons: u32,
+}
+
+struct Grid {
+    cell_size: f32,
+    buckets: Vec<Vec<u32>>,
+}
+
+impl Grid {
+    fn build(pixels: &[Pixel]) -> Grid {
+        let max_radius = pixels
+            .iter()
+            .filter(|pixel| pixel.visible.is_some())
+            .fold(0.0, |max, pixel| f32::max(max, pixel.radius));
+
+        let mut grid = Grid {
+            cell_size: f32::max(2.0 * max_radius, 1e-4),
+            buckets: vec![Vec::new(); pixels.len()],
+        };
+
+        for (index, pixel) in pixels.iter().enumerate() {
+            if let Some(visible) = &pixel.visible {
+                let radius = Vec3::from_scalar(pixel.radius);
+                let min = grid.cell(visible.point - radius);
+                let max = grid.cell(visible.point + radius);
+                for x in min.0..=max.0 {
+                    for y in min.1..=max.1 {
+                        for z in min.2..=max.2 {
+                            let bucket = grid.bucket((x, y, z));
+                            grid.buckets[bucket].push(index as u32);
+                        }
+                    }
+                }
+            }
+        }
+        grid
+    }
+
+    fn cell(&self, point: Vec3) -> (i32, i32, i32) {
+        (
+            f32::floor(point.x / self.cell_size) as i32,
+            f32::floor(point.y / self.cell_size) as i32,
+            f32::floor(point.z / self.cell_size) as i32,
+        )
+    }
+
+    fn bucket(&self, cell: (i32, i32, i32)) -> usize {
+        let hash = (cell.0 as u32).wrapping_mul(73856093)
+            ^ (cell.1 as u32).wrapping_mul(19349663)
+            ^ (cell.2 as u32).wrapping_mul(83492791);
+        hash as usize % self.buckets.len()
+    }
+
+    fn candidates(&self, point: Vec3) -> &[u32] {
+        &self.buckets[self.bucket(self.cell(point))]
+    }
+}
+
+pub fn render(
+    scene: &Scene,
+    camera: Camera,
+    width: i32,
+    height: i32,
+    settings: &SppmSettings,
+) -> Vec<Color> {
+    let mut pixels: Vec<Pixel> = (0..width * height)
+        .map(|_| Pixel {
+            radius: settings.initial_radius,
+            direct: BLACK,
+            photon_count: 0.0,
+            tau: BLACK,
+            visible: None,
+            phi: BLACK,
+            new_photons: 0,
+        })
+        .collect();
+
+    let num_threads = usize::max(settings.num_threads as usize, 1);
+    let rows_per_thread = (height as usize).div_ceil(num_threads);
+
+    for iteration in 0..settings.iterations {
+        std::thread::scope(|threads| {
+            for (band, chunk) in pixels
+                .chunks_mut(rows_per_thread * width as usize)
+                .enumerate()
+            {
+                let begin = band * rows_per_thread * width as usize;
+                let mut sampler =
+                    IndependentSampler::new(1, hash(&[settings.seed, iteration as u64]));
+                threads.spawn(move || {
+                    for (offset, pixel) in chunk.iter_mut().enumerate() {
+                        let index = begin + offset;
+                        let (x, y) = (index % width as usize, index / width as usize);
+                        sampler.start_pixel_sample(x as i32, y as i32, 0);
+                        let x = (x as f32 + sampler.next_1d()) / (width as f32 - 1.0);
+                        let y = (y as f32 + sampler.next_1d()) / (height as f32 - 1.0);
+                        let ray = camera.get_ray(x, y, &mut sampler);
+                        trace_visible_point(scene, ray, settings.max_depth, pixel, &mut sampler);
+                    }
+                });
+            }
+        });
+
+        // Every photon draws its random numbers from its own index and the threads' deposits
+        // are added in photon order, so the result doesn't depend on the thread count
+        let grid = Grid::build(&pixels);
+        let photon_seed = hash(&[settings.seed, iteration as u64, 1]);
+        for batch in (0..settings.photons).step_by(PHOTON_BATCH) {
+            let batch = batch..usize::min(batch + PHOTON_BATCH, settings.photons);
+            let per_thread = batch.len().div_ceil(num_threads);
+            let deposits: Vec<Vec<(u32, Color)>> = std::thread::scope(|threads| {
+                let handles: Vec<_> = (0..num_threads)
+                    .map(|thread| {
+                        let begin = usize::min(batch.start + thread * per_thread, batch.end);
+                        let photons = begin..usize::min(begin + per_thread, batch.end);
+                        let (pixels, grid) = (&pixels, &grid);
+                        let mut sampler = IndependentSampler::new(1, photon_seed);
+                        threads.spawn(move || {
+                            let mut deposits = Vec::new();
+                            for photon in photons {
+                                sampler.start_pixel_sample(photon as i32, 0, 0);
+                                let depth = settings.max_depth;
+                                let sampler = &mut sampler;
+                                trace_photon(scene, depth, pixels, grid, &mut deposits, sampler);
+                            }
+                            deposits
+                        })
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap())
+                    .collect()
+            });
+
+            for (index, flux) in deposits.into_iter().flatten() {
+                let pixel = &mut pixels[index as usize];
+                pixel.phi += flux;
+                pixel.new_photons += 1;
+            }
+        }
+
+        for pixel in pixels.iter_mut() {
+            let new_photons = std::mem::take(&mut pixel.new_photons) as f32;
+            let phi = std::mem::replace(&mut pixel.phi, BLACK);
+            if new_photons > 0.0 {
+                let count = pixel.photon_count + settings.alpha * new_photons;
+                let radius = pixel.radius * f32::sqrt(count / (pixel.photon_count + new_photons));
+                let beta = pixel.visible.as_ref().map_or(BLACK, |visible| visible.beta);
+                let shrink = (radius * radius) / (pixel.radius * pixel.radius);
+                pixel.tau = (pixel.tau + beta * phi) * shrink;
+                pixel.photon_count = count;
+                pixel.radius = radius;
+            }
+            pixel.visible = None;
+        }
+    }
+
+    let iterations = settings.iterations as f32;
+    let photons = settings.photons as f32;
+    pixels
+        .iter()
+        .map(|pixel| {
+            let area = PI * pixel.radius * pixel.radius;
+            pixel.direct * (1.0 / iterations) + pixel.tau * (1.0 / (iterations * photons * area))
+        })
+        .collect()
+}
+
+// Follows specular bounces from the camera, accumulating emission and direct lighting,
+// and stores the first diffuse hit as the pixel's visible point
+fn trace_visible_point(
+    scene: &Scene,
+    ray: Ray,
+    max_depth: i32,
+    pixel: &mut Pixel,
+    sampler: &mut dyn Sampler,
+) {
+    let mut ray = ray;
+    let mut beta = WHITE;
+
+    for _ in 0..max_depth {
+        let hit = match scene.world.hit(0.01, f32::INFINITY, &ray) {
+            Some(hit) => hit,
+            None => {
+                pixel.direct += beta * scene.background.radiance(ray.dir);
+                return;
+            }
+        };
+
+        pixel.direct += beta * hit.material.emitted();
+        let (attenuation, scattered) = match hit.material.scatter(ray, &hit, sampler) {
+            Some(scatter) => scatter,
+            None => return,
+        };
+
+        if hit.material.bsdf(&ray, &hit, scattered.dir).is_some() {
+            pixel.direct += beta * estimate_direct(ray, &hit, scene, sampler);
+            pixel.visible = Some(VisiblePoint {
+                point: hit.point,
+                ray,
+                hit,
+                beta,
+            });
+            return;
+        }
+
+        beta = beta * attenuation;
+        ray = scattered;
+    }
+}
+
+// Picks a light uniformly among emissive objects, analytic lights and the environment,
+// and returns a photon leaving it together with its power
+fn emit_photon(scene: &Scene, sampler: &mut dyn Sampler) -> Option<(Ray, Color)> {
+    let lights = &scene.lights;
+    let has_emitters = lights.emitter_count() > 0;
+    let sources =
+        has_emitters as usize + lights.analytic().len() + lights.has_environment() as usize;
+    if sources == 0 {
+        return None;
+    }
+
+    let bounds = scene.world.bounding_box()?;
+    let center = (bounds.min + bounds.max) * 0.5;
+    let radius = (bounds.max - bounds.min).length() * 0.5;
+
+    let mut choice = usize::min((sampler.next_1d() * sources as f32) as usize, sources - 1);
+    let selection = sources as f32;
+
+    if has_emitters {
+        if choice == 0 {
+            let sample = lights.sample_emitter_surface(sampler)?;
+            let mut normal = sample.normal;
+            if sampler.next_1d() < 0.5 {
+                normal = -normal;
+            }
+            let mut dir = normal + Vec3::random_unit_vector(sampler);
+            if dir.length2() < f32::EPSILON {
+                dir = normal;
+            }
+            // Cosine sampling over both sides cancels down to 2 pi
+            let power = sample.emission * (2.0 * PI * selection / sample.pdf);
+            return Some((Ray::new(sample.point, dir.normalized()), power));
+        }
+        choice -= 1;
+    }
+
+    if let Some(light) = lights.analytic().get(choice) {
+        let (ray, power) = light.sample_emission(center, radius, sampler);
+        return Some((ray, power * selection));
+    }
+
+    let environment = lights.environment()?;
+    let dir = environment.random_direction(sampler);
+    let pdf = environment.pdf_value(dir);
+    if pdf <= 0.0 {
+        return None;
+    }
+    let dir = dir.normalized();
+    let origin = sample_disk(center + dir * radius, dir, radius, sampler);
+    let power = environment.radiance(dir) * (PI * radius * radius * selection / pdf);
+    Some((Ray::new(origin, -dir), power))
+}
+
+// Appends the flux the photon leaves at each visible point it passes, by pixel index
+fn trace_photon(
+    scene: &Scene,
+    max_depth: i32,
+    pixels: &[Pixel],
+    grid: &Grid,
+    deposits: &mut Vec<(u32, Color)>,
+    sampler: &mut dyn Sampler,
+) {
+    let (mut ray, mut beta) = match emit_photon(scene, sampler) {
+        Some(photon) => photon,
+        None => return,
+    };
+
+    for depth in 0..max_depth {
+        let hit = match scene.world.hit(0.01, f32::INFINITY, &ray) {
+            Some(hit) => hit,
+            None => return,
+        };
+
+        // Direct lighting is already part of each pixel's estimate
+        if depth > 0 {
+            let wi = -ray.dir.normalized();
+            for &index in grid.candidates(hit.point) {
+                let pixel = &pixels[index as usize];
+                let visible = match &pixel.visible {
+                    Some(visible) => visible,
+                    None => continue,
+                };
+                if (visible.point - hit.point).length2() > pixel.radius * pixel.radius {
+                    continue;
+                }
+
+                let cosine = Vec3::dot(wi, visible.hit.normal);
+                if cosine <= 0.0 {
+                    continue;
+                }
+                if let Some((f, _)) = visible.hit.material.bsdf(&visible.ray, &visible.hit, wi) {
+                    deposits.push((index, beta * f * (1.0 / cosine)));
+                }
+            }
+        }
+
+        let (attenuation, scattered) = match hit.material.scatter(ray, &hit, sampler) {
+            Some(scatter) => scatter,
+            None => return,
+        };
+
+        // Russian roulette on the change in throughput keeps photon powers even
+        let new_beta = beta * attenuation;
+        let survival = f32::min(1.0, new_beta.luminance() / f32::max(beta.luminance(), 1e-8));
+        if sampler.next_1d() >= survival {
+            return;
+        }
+        beta = new_beta * (1.0 / survival);
+        ray = scattered;
+    }
+}
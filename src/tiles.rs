@@ -0,0 +1,87 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const TILE_SIZE: i32 = 32;
+
+// Rectangle of pixels [x0, x1) x [y0, y1)
+#[derive(Clone, Copy, Debug)]
+pub struct Tile {
+    pub x0: i32,
+    pub y0: i32,
+    pub x1: i32,
+    pub y1: i32,
+}
+
+impl Tile {
+    pub fn width(&self) -> i32 {
+        self.x1 - self.x0
+    }
+
+    pub fn height(&self) -> i32 {
+        self.y1 - self.y0
+    }
+
+    pub fn pixel_count(&self) -> usize {
+        (self.width() * self.height()) as usize
+    }
+
+    // Copies row-major tile pixels into the full image
+    pub fn write_to<T: Copy>(&self, pixels: &[T], image: &mut [T], image_width: i32) {
+        for (row, y) in (self.y0..self.y1).enumerate() {
+            let source = &pixels[row * self.width() as usize..][..self.width() as usize];
+            let start = (y * image_width + self.x0) as usize;
+            image[start..start + source.len()].copy_from_slice(source);
+        }
+    }
+}
+
+// Covers the image with tiles, ordered in a spiral going out from the centre so that the
+// interesting part of the image finishes first
+pub fn spiral(width: i32, height: i32, size: i32) -> Vec<Tile> {
+    let tiles_x = (width + size - 1) / size;
+    let tiles_y = (height + size - 1) / size;
+    let count = (tiles_x * tiles_y) as usize;
+    let tile = |tx: i32, ty: i32| Tile {
+        x0: tx * size,
+        y0: ty * size,
+        x1: i32::min((tx + 1) * size, width),
+        y1: i32::min((ty + 1) * size, height),
+    };
+
+    let mut tiles = Vec::with_capacity(count);
+    let (mut tx, mut ty) = ((tiles_x - 1) / 2, (tiles_y - 1) / 2);
+    let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let mut leg = 0;
+    while tiles.len() < count {
+        // Legs grow by one every two turns: 1, 1, 2, 2, 3, 3, ...
+        let (dx, dy) = directions[leg % 4];
+        for _ in 0..leg / 2 + 1 {
+            if (0..tiles_x).contains(&tx) && (0..tiles_y).contains(&ty) {
+                tiles.push(tile(tx, ty));
+            }
+            tx += dx;
+            ty += dy;
+        }
+        leg += 1;
+    }
+    tiles
+}
+
+// Hands out tiles to worker threads in order, one at a time
+pub struct TileQueue {
+    tiles: Vec<Tile>,
+    next: AtomicUsize,
+}
+
+impl TileQueue {
+    pub fn new(tiles: Vec<Tile>) -> TileQueue {
+        TileQueue {
+            tiles,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn next(&self) -> Option<(usize, Tile)> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed);
+        self.tiles.get(index).map(|&tile| (index, tile))
+    }
+}
@@ -0,0 +1,191 @@
+use crate::color::Color;
+
+type Matrix = [[f32; 3]; 3];
+
+const IDENTITY: Matrix = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+const SRGB_TO_XYZ: Matrix = [
+    [0.4124564, 0.3575761, 0.1804375],
+    [0.2126729, 0.7151522, 0.0721750],
+    [0.0193339, 0.119192, 0.9503041],
+];
+
+const XYZ_TO_SRGB: Matrix = [
+    [3.2404542, -1.5371385, -0.4985314],
+    [-0.969266, 1.8760108, 0.0415560],
+    [0.0556434, -0.2040259, 1.0572252],
+];
+
+// Bradford cone response, used to adapt one white point to another
+const XYZ_TO_LMS: Matrix = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+
+const LMS_TO_XYZ: Matrix = [
+    [0.9869929, -0.1470543, 0.1599627],
+    [0.4323053, 0.5183603, 0.0492912],
+    [-0.0085287, 0.0400428, 0.9684867],
+];
+
+// Chromaticity of D65, the white point of sRGB
+const D65: (f32, f32) = (0.3127, 0.3290);
+
+// Curves mapping exposed radiance to display values in [0, 1]
+#[derive(Clone, Copy, Debug)]
+pub enum Curve {
+    Clamp,
+    Reinhard,
+    Filmic,
+    Aces,
+}
+
+impl Curve {
+    pub fn from_name(name: &str) -> Option<Curve> {
+        match name {
+            "clamp" => Some(Curve::Clamp),
+            "reinhard" => Some(Curve::Reinhard),
+            "filmic" | "hable" => Some(Curve::Filmic),
+            "aces" => Some(Curve::Aces),
+            _ => None,
+        }
+    }
+
+    fn apply(self, color: Color) -> Color {
+        match self {
+            Curve::Clamp => color,
+            // On luminance so bright colors keep their hue instead of washing out
+            Curve::Reinhard => color * (1.0 / (1.0 + f32::max(color.luminance(), 0.0))),
+            Curve::Filmic => {
+                let scale = 1.0 / hable(11.2);
+                let channel = |value: f32| hable(2.0 * f32::max(value, 0.0)) * scale;
+                Color::new(channel(color.r), channel(color.g), channel(color.b))
+            }
+            Curve::Aces => aces(color),
+        }
+    }
+}
+
+// Uncharted 2 filmic curve by John Hable
+fn hable(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+}
+
+// Stephen Hill's fit of the ACES reference rendering and sRGB output transforms
+fn aces(color: Color) -> Color {
+    const INPUT: Matrix = [
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ];
+    const OUTPUT: Matrix = [
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ];
+    let fit =
+        |v: f32| (v * (v + 0.0245786) - 0.000090537) / (v * (0.983729 * v + 0.432951) + 0.238081);
+    let color = transform(&INPUT, color);
+    transform(
+        &OUTPUT,
+        Color::new(fit(color.r), fit(color.g), fit(color.b)),
+    )
+}
+
+// Turns the radiance of a render into what an output shows: exposure and white balance are
+// linear and apply to every format, the curve only to 8-bit images
+#[derive(Clone, Copy, Debug)]
+pub struct ToneMapping {
+    // In stops, each one doubles the brightness
+    pub exposure: f32,
+    pub curve: Curve,
+    // Left out when not set, so values like an infinite depth pass through unchanged
+    white_balance: Option<Matrix>,
+}
+
+impl ToneMapping {
+    pub fn new() -> ToneMapping {
+        ToneMapping {
+            exposure: 0.0,
+            curve: Curve::Clamp,
+            white_balance: None,
+        }
+    }
+
+    // Makes light of this color temperature in Kelvin look white
+    pub fn set_white_balance(&mut self, kelvin: f32) {
+        let white = chromaticity_to_lms(planckian_locus(kelvin));
+        let target = chromaticity_to_lms(D65);
+        let mut gains = IDENTITY;
+        for (i, row) in gains.iter_mut().enumerate() {
+            row[i] = target[i] / white[i];
+        }
+        let to_lms = multiply(&XYZ_TO_LMS, &SRGB_TO_XYZ);
+        let from_lms = multiply(&XYZ_TO_SRGB, &LMS_TO_XYZ);
+        self.white_balance = Some(multiply(&from_lms, &multiply(&gains, &to_lms)));
+    }
+
+    // Exposure and white balance only, for formats that keep the full range
+    pub fn linear(&self, color: Color) -> Color {
+        let color = match &self.white_balance {
+            Some(white_balance) => transform(white_balance, color),
+            None => color,
+        };
+        color * 2f32.powf(self.exposure)
+    }
+
+    pub fn linear_image(&self, image: &[Color]) -> Vec<Color> {
+        image.iter().map(|&color| self.linear(color)).collect()
+    }
+
+    // Linear display values in [0, 1], still to be encoded for the display
+    pub fn display(&self, color: Color) -> Color {
+        let color = self.curve.apply(self.linear(color));
+        let clamp = |value: f32| value.clamp(0.0, 1.0);
+        Color::new(clamp(color.r), clamp(color.g), clamp(color.b))
+    }
+}
+
+// CIE xy of a black body, Kang et al. 2002 cubic spline fit for 1667 K to 25000 K
+fn planckian_locus(kelvin: f32) -> (f32, f32) {
+    let t = kelvin.clamp(1667.0, 25000.0);
+    let (t2, t3) = (t * t, t * t * t);
+    let x = if t <= 4000.0 {
+        -0.2661239e9 / t3 - 0.2343589e6 / t2 + 0.8776956e3 / t + 0.179910
+    } else {
+        -3.0258469e9 / t3 + 2.107038e6 / t2 + 0.2226347e3 / t + 0.240390
+    };
+    let (x2, x3) = (x * x, x * x * x);
+    let y = if t <= 2222.0 {
+        -1.1063814 * x3 - 1.3481102 * x2 + 2.1855583 * x - 0.20219683
+    } else if t <= 4000.0 {
+        -0.9549476 * x3 - 1.3741859 * x2 + 2.09137 * x - 0.16748867
+    } else {
+        3.081758 * x3 - 5.873387 * x2 + 3.7511299 * x - 0.37001483
+    };
+    (x, y)
+}
+
+// Cone response to a white of unit luminance
+fn chromaticity_to_lms((x, y): (f32, f32)) -> [f32; 3] {
+    let xyz = Color::new(x / y, 1.0, (1.0 - x - y) / y);
+    let lms = transform(&XYZ_TO_LMS, xyz);
+    [lms.r, lms.g, lms.b]
+}
+
+fn transform(m: &Matrix, c: Color) -> Color {
+    let row = |i: usize| m[i][0] * c.r + m[i][1] * c.g + m[i][2] * c.b;
+    Color::new(row(0), row(1), row(2))
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut product = [[0.0; 3]; 3];
+    for (i, row) in product.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    product
+}